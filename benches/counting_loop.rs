@@ -0,0 +1,50 @@
+//! Times a million-iteration counting loop, whose `i = i + 1` becomes a single `IncrementLocal`
+//! with superinstructions. Run with `cargo bench --bench counting_loop`.
+
+use std::time::{Duration, Instant};
+
+use lox_v2::chunk::Chunk;
+use lox_v2::compiler;
+use lox_v2::globals::Globals;
+use lox_v2::object::Allocator;
+use lox_v2::vm::{Dispatch, Vm};
+
+const SOURCE: &str = "{ var i = 0; while (i < 1000000) i = i + 1; }";
+const RUNS: u32 = 10;
+
+fn main() {
+	let mut objects = Allocator::default();
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		for superinstructions in [false, true] {
+			let mut chunk = Chunk::default();
+			compiler::compile(
+				SOURCE,
+				&mut chunk,
+				false,
+				&mut objects,
+				&mut Globals::default(),
+			)
+			.unwrap();
+			if superinstructions {
+				chunk.fuse_superinstructions();
+			}
+
+			let elapsed = time(dispatch, &mut chunk);
+			println!(
+				"{dispatch:?}, superinstructions: {superinstructions}: {RUNS} runs in {elapsed:?} \
+				 ({:?} per run)",
+				elapsed / RUNS
+			);
+		}
+	}
+}
+
+fn time(dispatch: Dispatch, chunk: &mut Chunk) -> Duration {
+	let mut vm = Vm::new(std::io::sink());
+	vm.dispatch = dispatch;
+	let start = Instant::now();
+	for _ in 0..RUNS {
+		vm.run(chunk).unwrap();
+	}
+	start.elapsed()
+}
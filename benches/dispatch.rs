@@ -0,0 +1,50 @@
+//! Compares the two interpreter loops, see [Dispatch]. Run with `cargo bench --bench dispatch`.
+
+use std::time::{Duration, Instant};
+
+use lox_v2::chunk::Chunk;
+use lox_v2::compiler;
+use lox_v2::globals::Globals;
+use lox_v2::object::Allocator;
+use lox_v2::vm::{Dispatch, Vm};
+
+const STATEMENTS: usize = 50;
+const ITERATIONS: usize = 20_000;
+
+fn main() {
+	// Locals and arithmetic only, so the chunk doesn't depend on the VM's globals
+	let mut source = String::from("{ var a = 1; var b = 2; var c = 3;\n");
+	for _ in 0..STATEMENTS {
+		source.push_str("(a + b) * c - a / b + (c - a) * (b + c) > a == !(b < c);\n");
+	}
+	source.push('}');
+
+	let mut objects = Allocator::default();
+	let mut chunk = Chunk::default();
+	compiler::compile(
+		&source,
+		&mut chunk,
+		false,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let elapsed = time(dispatch, &mut chunk);
+		println!(
+			"{dispatch:?}: {ITERATIONS} runs in {elapsed:?} ({:?} per run)",
+			elapsed / ITERATIONS as u32
+		);
+	}
+}
+
+fn time(dispatch: Dispatch, chunk: &mut Chunk) -> Duration {
+	let mut vm = Vm::new(std::io::sink());
+	vm.dispatch = dispatch;
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		vm.run(chunk).unwrap();
+	}
+	start.elapsed()
+}
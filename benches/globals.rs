@@ -0,0 +1,27 @@
+//! Times a script that mostly reads global variables. Run with `cargo bench --bench globals`.
+
+use std::time::Instant;
+
+use lox_v2::vm::Vm;
+
+const READS: usize = 2_000;
+const ITERATIONS: usize = 200;
+
+fn main() {
+	let mut source = String::from("var a = 1; var b = 2; var c = 3;\n");
+	for _ in 0..READS {
+		source.push_str("a + b + c;\n");
+	}
+
+	let mut vm = Vm::new(std::io::sink());
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		vm.interpret(&source).unwrap();
+	}
+	let elapsed = start.elapsed();
+	println!(
+		"{ITERATIONS} runs of {} global reads: {elapsed:?} ({:?} per run)",
+		READS * 3,
+		elapsed / ITERATIONS as u32
+	);
+}
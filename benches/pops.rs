@@ -0,0 +1,59 @@
+//! Compares running a script with many statements with and without merging the pops that follow
+//! each other into one `PopN`. Run with `cargo bench --bench pops`.
+
+use std::time::{Duration, Instant};
+
+use lox_v2::chunk::Chunk;
+use lox_v2::compiler::{self, Options};
+use lox_v2::globals::Globals;
+use lox_v2::object::Allocator;
+use lox_v2::vm::{Dispatch, Vm};
+
+const STATEMENTS: usize = 200;
+const ITERATIONS: usize = 20_000;
+
+fn main() {
+	// The pop of each block's last expression statement merges with the pops of its locals.
+	// Without number literals, a chunk holds only 256 constants.
+	let mut source = String::new();
+	for _ in 0..STATEMENTS {
+		source.push_str("{ var a = true; var b = false; a == b; { var c = a; !c; } }\n");
+	}
+
+	let mut objects = Allocator::default();
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		for merge_pops in [false, true] {
+			let mut chunk = Chunk::default();
+			compiler::compile_with_options(
+				&source,
+				&mut chunk,
+				false,
+				&mut objects,
+				&mut Globals::default(),
+				Options {
+					merge_pops,
+					..Options::default()
+				},
+			)
+			.unwrap();
+
+			let elapsed = time(dispatch, &mut chunk);
+			println!(
+				"{dispatch:?}, merge pops: {merge_pops}: {ITERATIONS} runs of {} bytes in \
+				 {elapsed:?} ({:?} per run)",
+				chunk.code_len(),
+				elapsed / ITERATIONS as u32
+			);
+		}
+	}
+}
+
+fn time(dispatch: Dispatch, chunk: &mut Chunk) -> Duration {
+	let mut vm = Vm::new(std::io::sink());
+	vm.dispatch = dispatch;
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		vm.run(chunk).unwrap();
+	}
+	start.elapsed()
+}
@@ -0,0 +1,72 @@
+//! Compares running arithmetic and unary operators in a loop with and without superinstructions.
+//! Run with `cargo bench --bench superinstructions`.
+
+use std::time::{Duration, Instant};
+
+use lox_v2::chunk::Chunk;
+use lox_v2::compiler;
+use lox_v2::globals::Globals;
+use lox_v2::object::Allocator;
+use lox_v2::vm::{Dispatch, Vm};
+
+const STATEMENTS: usize = 10;
+const LOOPS: usize = 100;
+const ITERATIONS: usize = 2_000;
+
+fn main() {
+	let workloads = [
+		(
+			"arithmetic",
+			"{ var a = 1; var b = 2; var c = 3;\n",
+			"a + b + 1 + (c + 2) * (a + c) - (b + 3) + a * 4 + 5;\n",
+		),
+		(
+			"unary",
+			"{ var x = 1; var b = true; var y = -2; var s = \"\";\n",
+			"-x; !b; -y; !s; -x + -y; !b == !s;\n",
+		),
+	];
+
+	let mut objects = Allocator::default();
+	for (name, prologue, statement) in workloads {
+		let mut source = format!("{prologue}var i = 0; while (i < {LOOPS}) {{\n");
+		for _ in 0..STATEMENTS {
+			source.push_str(statement);
+		}
+		source.push_str("i = i + 1; } }");
+
+		for dispatch in [Dispatch::Match, Dispatch::Table] {
+			for superinstructions in [false, true] {
+				let mut chunk = Chunk::default();
+				compiler::compile(
+					&source,
+					&mut chunk,
+					false,
+					&mut objects,
+					&mut Globals::default(),
+				)
+				.unwrap();
+				if superinstructions {
+					chunk.fuse_superinstructions();
+				}
+
+				let elapsed = time(dispatch, &mut chunk);
+				println!(
+					"{name}, {dispatch:?}, superinstructions: {superinstructions}: {ITERATIONS} \
+					 runs in {elapsed:?} ({:?} per run)",
+					elapsed / ITERATIONS as u32
+				);
+			}
+		}
+	}
+}
+
+fn time(dispatch: Dispatch, chunk: &mut Chunk) -> Duration {
+	let mut vm = Vm::new(std::io::sink());
+	vm.dispatch = dispatch;
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		vm.run(chunk).unwrap();
+	}
+	start.elapsed()
+}
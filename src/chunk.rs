@@ -4,10 +4,25 @@ use thiserror::Error;
 
 use crate::value::Value;
 
-#[derive(Debug, Copy, Clone)]
-#[repr(u8)]
-pub enum OpCode {
-	Constant = 0,
+/// Declares [OpCode] along with [OpCode::ALL], so the list of opcodes can't go out of sync with
+/// the enum
+macro_rules! opcodes {
+	($($name:ident,)*) => {
+		#[derive(Debug, Copy, Clone)]
+		#[repr(u8)]
+		pub enum OpCode {
+			$($name,)*
+		}
+
+		impl OpCode {
+			/// Every opcode, indexed by its byte
+			pub const ALL: &'static [OpCode] = &[$(OpCode::$name,)*];
+		}
+	};
+}
+
+opcodes! {
+	Constant,
 	Nil,
 	True,
 	False,
@@ -18,12 +33,41 @@ pub enum OpCode {
 	Subtract,
 	Multiply,
 	Divide,
+	FloorDivide,
+	Modulo,
+	FloorModulo,
+	Power,
 	Not,
 	Negate,
 	Print,
+	PrintSep,
 	Pop,
+	PopN,
+	Dup,
+	Swap,
 	DefineGlobal,
 	GetGlobal,
+	SetGlobal,
+	GetLocal,
+	SetLocal,
+	Jump,
+	Loop,
+	JumpIfFalse,
+	JumpIfNotNil,
+	PushHandler,
+	PopHandler,
+	Throw,
+	Import,
+	Call,
+	IsType,
+	CheckCount,
+	// Superinstructions, see [Chunk::fuse_superinstructions]
+	LocalLocal,
+	LocalConstant,
+	ConstantAdd,
+	LocalNegate,
+	LocalNot,
+	IncrementLocal,
 	Return,
 }
 
@@ -33,44 +77,109 @@ impl From<OpCode> for u8 {
 	}
 }
 
-impl Display for OpCode {
-	fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+impl OpCode {
+	/// Length of the longest opcode name, the width of the name column in disassembly
+	pub const NAME_WIDTH: usize = {
+		let mut width = 0;
+		let mut i = 0;
+		while i < OpCode::ALL.len() {
+			let len = OpCode::ALL[i].name().len();
+			if len > width {
+				width = len;
+			}
+			i += 1;
+		}
+		width
+	};
+
+	/// Name of the opcode in disassembly
+	pub const fn name(self) -> &'static str {
 		match self {
-			OpCode::Constant => f.pad("OP_CONSTANT"),
-			OpCode::Nil => f.pad("OP_NIL"),
-			OpCode::True => f.pad("OP_TRUE"),
-			OpCode::False => f.pad("OP_FALSE"),
-			OpCode::Equal => f.pad("OP_EQUAL"),
-			OpCode::Greater => f.pad("OP_GREATER"),
-			OpCode::Less => f.pad("OP_LESS"),
-			OpCode::Add => f.pad("OP_ADD"),
-			OpCode::Subtract => f.pad("OP_SUBTRACT"),
-			OpCode::Multiply => f.pad("OP_MULTIPLY"),
-			OpCode::Divide => f.pad("OP_DIVIDE"),
-			OpCode::Negate => f.pad("OP_NEGATE"),
-			OpCode::Not => f.pad("OP_NOT"),
-			OpCode::Print => f.pad("OP_PRINT"),
-			OpCode::Pop => f.pad("OP_POP"),
-			OpCode::DefineGlobal => f.pad("OP_DEFINE_GLOBAL"),
-			OpCode::GetGlobal => f.pad("OP_GET_GLOBAL"),
-			OpCode::Return => f.pad("OP_RETURN"),
+			OpCode::Constant => "OP_CONSTANT",
+			OpCode::Nil => "OP_NIL",
+			OpCode::True => "OP_TRUE",
+			OpCode::False => "OP_FALSE",
+			OpCode::Equal => "OP_EQUAL",
+			OpCode::Greater => "OP_GREATER",
+			OpCode::Less => "OP_LESS",
+			OpCode::Add => "OP_ADD",
+			OpCode::Subtract => "OP_SUBTRACT",
+			OpCode::Multiply => "OP_MULTIPLY",
+			OpCode::Divide => "OP_DIVIDE",
+			OpCode::FloorDivide => "OP_FLOOR_DIVIDE",
+			OpCode::Modulo => "OP_MODULO",
+			OpCode::FloorModulo => "OP_FLOOR_MODULO",
+			OpCode::Power => "OP_POWER",
+			OpCode::Negate => "OP_NEGATE",
+			OpCode::Not => "OP_NOT",
+			OpCode::Print => "OP_PRINT",
+			OpCode::PrintSep => "OP_PRINT_SEP",
+			OpCode::Pop => "OP_POP",
+			OpCode::PopN => "OP_POPN",
+			OpCode::Dup => "OP_DUP",
+			OpCode::Swap => "OP_SWAP",
+			OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+			OpCode::GetGlobal => "OP_GET_GLOBAL",
+			OpCode::SetGlobal => "OP_SET_GLOBAL",
+			OpCode::GetLocal => "OP_GET_LOCAL",
+			OpCode::SetLocal => "OP_SET_LOCAL",
+			OpCode::Jump => "OP_JUMP",
+			OpCode::Loop => "OP_LOOP",
+			OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+			OpCode::JumpIfNotNil => "OP_JUMP_IF_NOT_NIL",
+			OpCode::PushHandler => "OP_PUSH_HANDLER",
+			OpCode::PopHandler => "OP_POP_HANDLER",
+			OpCode::Throw => "OP_THROW",
+			OpCode::Import => "OP_IMPORT",
+			OpCode::Call => "OP_CALL",
+			OpCode::IsType => "OP_IS_TYPE",
+			OpCode::CheckCount => "OP_CHECK_COUNT",
+			OpCode::LocalLocal => "OP_LOCAL_LOCAL",
+			OpCode::LocalConstant => "OP_LOCAL_CONSTANT",
+			OpCode::ConstantAdd => "OP_CONSTANT_ADD",
+			OpCode::LocalNegate => "OP_LOCAL_NEGATE",
+			OpCode::LocalNot => "OP_LOCAL_NOT",
+			OpCode::IncrementLocal => "OP_INCREMENT_LOCAL",
+			OpCode::Return => "OP_RETURN",
 		}
 	}
 }
 
+impl Display for OpCode {
+	fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+		f.pad(self.name())
+	}
+}
+
 #[derive(Debug, Error)]
 #[error("Unknown opcode {0}")]
 pub struct UnknownOpCode(u8);
 
+/// Bytes that can't be decoded into an [Instruction], in a chunk that was built by hand or got
+/// corrupted
+#[derive(Debug, Error)]
+pub enum DecodeError {
+	#[error(transparent)]
+	UnknownOpCode(#[from] UnknownOpCode),
+
+	#[error("Instruction at {offset} refers to constant {idx}, which doesn't exist")]
+	MalformedConstant { offset: usize, idx: usize },
+
+	#[error("Instruction at {offset} is missing operands")]
+	Truncated { offset: usize },
+
+	#[error("Jump at {offset} goes before the start of the chunk")]
+	JumpOutOfRange { offset: usize },
+}
+
 impl TryFrom<u8> for OpCode {
 	type Error = UnknownOpCode;
 
 	fn try_from(value: u8) -> Result<Self, Self::Error> {
-		if value > OpCode::Return as u8 {
-			Err(UnknownOpCode(value))
-		} else {
-			unsafe { Ok(std::mem::transmute::<u8, OpCode>(value)) }
-		}
+		OpCode::ALL
+			.get(value as usize)
+			.copied()
+			.ok_or(UnknownOpCode(value))
 	}
 }
 
@@ -84,8 +193,119 @@ pub struct Chunk {
 impl Chunk {
 	pub fn write(&mut self, v: impl Into<u8>, line: usize) {
 		self.code.push(v.into());
-		self.lines.resize_with(self.code.len(), Default::default);
-		self.lines.insert(self.code.len() - 1, line);
+		self.lines.resize(self.code.len() - 1, 0);
+		self.lines.push(line);
+	}
+
+	/// Source line of the instruction at `offset`. Falls back to the last known line for offsets
+	/// past the end of the line table, and to 0 if it's empty.
+	pub fn line(&self, offset: usize) -> usize {
+		self.line_at(offset)
+			.or(self.lines.last().copied())
+			.unwrap_or_default()
+	}
+
+	/// Source line of the byte at `offset`, `None` if it's past the end of the line table
+	pub fn line_at(&self, offset: usize) -> Option<usize> {
+		self.lines.get(offset).copied()
+	}
+
+	pub fn code_len(&self) -> usize {
+		self.code.len()
+	}
+
+	pub fn code(&self) -> &[u8] {
+		&self.code
+	}
+
+	pub fn constants(&self) -> &[Value] {
+		&self.constants
+	}
+
+	pub(crate) fn constant(&self, idx: usize) -> Option<Value> {
+		self.constants.get(idx).copied()
+	}
+
+	/// Drops the code from `len` on, for the compiler to rewrite the last instruction
+	pub(crate) fn truncate(&mut self, len: usize) {
+		self.code.truncate(len);
+		self.lines.truncate(len);
+	}
+
+	pub(crate) fn set_constant(&mut self, idx: usize, v: Value) {
+		self.constants[idx] = v;
+	}
+
+	/// Overwrites the two byte operand of a jump instruction at `offset`
+	pub fn patch_jump(&mut self, offset: usize, jump: u16) {
+		let [hi, lo] = jump.to_be_bytes();
+		self.code[offset] = hi;
+		self.code[offset + 1] = lo;
+	}
+
+	/// Replaces common pairs of instructions with superinstructions that do the work of both in
+	/// one dispatch: `GetLocal GetLocal`, `GetLocal Constant`, `Constant Add`, `GetLocal Negate`
+	/// and `GetLocal Not`. Counting loops' `i = i + 1`, `GetLocal Constant Add SetLocal` on the
+	/// same slot, becomes a single `IncrementLocal`.
+	///
+	/// Only the opcode of the first instruction is overwritten. A superinstruction spans the bytes
+	/// of all the originals and the rest are left intact, so offsets don't change and a jump
+	/// landing on a later instruction still runs the originals from there.
+	pub fn fuse_superinstructions(&mut self) {
+		let mut offset = 0;
+		while let Some(Ok(first)) = self.decode_instruction(offset) {
+			if let Some(len) = self.increment_local_len(offset, &first) {
+				self.code[offset] = OpCode::IncrementLocal as u8;
+				offset += len;
+				continue;
+			}
+			let next = offset + first.byte_len();
+			let Some(Ok(second)) = self.decode_instruction(next) else {
+				break;
+			};
+			let fused = match (first.opcode, second.opcode) {
+				(OpCode::GetLocal, OpCode::GetLocal) => OpCode::LocalLocal,
+				(OpCode::GetLocal, OpCode::Constant) => OpCode::LocalConstant,
+				(OpCode::Constant, OpCode::Add) => OpCode::ConstantAdd,
+				(OpCode::GetLocal, OpCode::Negate) => OpCode::LocalNegate,
+				(OpCode::GetLocal, OpCode::Not) => OpCode::LocalNot,
+				_ => {
+					offset = next;
+					continue;
+				}
+			};
+			self.code[offset] = fused as u8;
+			offset = next + second.byte_len();
+		}
+	}
+
+	/// Length of the `GetLocal Constant Add SetLocal` run starting with `first` at `offset`, `None`
+	/// if the instructions there don't make one or read and write different slots. The compiler
+	/// emits `local = local + constant` like any other assignment, it's recognized here after
+	/// compiling so that it's fused only when superinstructions are on.
+	fn increment_local_len(&self, offset: usize, first: &Instruction) -> Option<usize> {
+		let mut len = first.byte_len();
+		let mut next = || {
+			let instruction = self.decode_instruction(offset + len)?.ok()?;
+			len += instruction.byte_len();
+			Some(instruction)
+		};
+		let (constant, add, set) = (next()?, next()?, next()?);
+		match (first, constant.opcode, add.opcode, set) {
+			(
+				Instruction {
+					opcode: OpCode::GetLocal,
+					kind: InstructionKind::Byte { operand: get_slot },
+				},
+				OpCode::Constant,
+				OpCode::Add,
+				Instruction {
+					opcode: OpCode::SetLocal,
+					kind: InstructionKind::Byte { operand: set_slot },
+				},
+			) if *get_slot == set_slot => Some(len),
+			_ => None,
+		}
 	}
 
 	pub fn write_constant(&mut self, v: Value) -> usize {
@@ -110,6 +330,7 @@ impl Chunk {
 
 			match iter.next() {
 				Some(Ok(instruction)) => {
+					writeln!(w)?;
 					self.disassemble_instruction_to_write(offset, &instruction, w)?
 				}
 				Some(Err(err)) => write!(w, "\n{offset:04} {err}")?,
@@ -129,16 +350,10 @@ impl Chunk {
 	where
 		W: std::fmt::Write,
 	{
-		let line = self
-			.lines
-			.get(offset)
-			.cloned()
-			.expect("code and lines arrays out of sync");
-
+		let line = self.line(offset);
 		let same_line = offset
 			.checked_sub(1)
-			.and_then(|offset| self.lines.get(offset))
-			.map(|previous_line| line == *previous_line)
+			.map(|previous| self.line(previous) == line)
 			.unwrap_or_default();
 
 		write!(w, "{offset:04} ")?;
@@ -153,24 +368,115 @@ impl Chunk {
 		Ok(())
 	}
 
-	pub fn decode_instruction(&self, offset: usize) -> Option<Result<Instruction, UnknownOpCode>> {
-		let instruction = self.code.get(offset)?;
+	/// Decodes the instruction at `offset`. `None` past the end of the code, an error if the
+	/// bytes there don't make up a valid instruction.
+	pub fn decode_instruction(&self, offset: usize) -> Option<Result<Instruction, DecodeError>> {
+		let opcode = *self.code.get(offset)?;
+		Some(self.decode_at(offset, opcode))
+	}
 
-		let opcode = match OpCode::try_from(*instruction) {
-			Ok(v) => v,
-			Err(err) => return Some(Err(err)),
+	fn decode_at(&self, offset: usize, opcode: u8) -> Result<Instruction, DecodeError> {
+		let opcode = OpCode::try_from(opcode)?;
+		let byte = |i: usize| {
+			self.code
+				.get(offset + i)
+				.copied()
+				.ok_or(DecodeError::Truncated { offset })
+		};
+		let short = |i: usize| -> Result<usize, DecodeError> {
+			Ok(u16::from_be_bytes([byte(i)?, byte(i + 1)?]) as usize)
+		};
+		let constant = |idx: usize| {
+			self.constants
+				.get(idx)
+				.copied()
+				.ok_or(DecodeError::MalformedConstant { offset, idx })
 		};
 
-		match opcode {
-			OpCode::Return => Some(Ok(Instruction::simple(opcode))),
+		let instruction = match opcode {
+			OpCode::Constant | OpCode::Import => {
+				let idx = byte(1)? as usize;
+				Instruction::constant(opcode, constant(idx)?, idx)
+			}
+
+			OpCode::PopN | OpCode::GetLocal | OpCode::SetLocal | OpCode::Call | OpCode::IsType => {
+				Instruction::byte(opcode, byte(1)?)
+			}
+
+			OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => {
+				Instruction::short(opcode, short(1)? as u16)
+			}
+
+			OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNotNil | OpCode::PushHandler => {
+				Instruction::jump(opcode, offset + 3 + short(1)?)
+			}
+
+			OpCode::Loop => {
+				let target = (offset + 3)
+					.checked_sub(short(1)?)
+					.ok_or(DecodeError::JumpOutOfRange { offset })?;
+				Instruction::jump(opcode, target)
+			}
+
+			OpCode::LocalLocal => Instruction::fused(
+				opcode,
+				Fused::LocalLocal {
+					first: byte(1)?,
+					second: byte(3)?,
+				},
+			),
+
+			OpCode::LocalConstant => {
+				let idx = byte(3)? as usize;
+				Instruction::fused(
+					opcode,
+					Fused::LocalConstant {
+						slot: byte(1)?,
+						v: constant(idx)?,
+						idx,
+					},
+				)
+			}
+
+			OpCode::ConstantAdd => {
+				let idx = byte(1)? as usize;
+				// The original opcode byte of the second instruction follows the operand
+				byte(2)?;
+				Instruction::fused(
+					opcode,
+					Fused::ConstantAdd {
+						v: constant(idx)?,
+						idx,
+					},
+				)
+			}
 
-			OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal => {
-				let constant_idx = *self.code.get(offset + 1)? as usize;
-				let constant = self.constants.get(constant_idx)?.clone();
-				Some(Ok(Instruction::constant(opcode, constant, constant_idx)))
+			OpCode::LocalNegate | OpCode::LocalNot => {
+				let slot = byte(1)?;
+				byte(2)?;
+				let fused = match opcode {
+					OpCode::LocalNegate => Fused::LocalNegate { slot },
+					_ => Fused::LocalNot { slot },
+				};
+				Instruction::fused(opcode, fused)
 			}
 
-			OpCode::Nil
+			OpCode::IncrementLocal => {
+				let idx = byte(3)? as usize;
+				// The `SetLocal` writing the sum back ends the run of originals
+				byte(6)?;
+				Instruction::fused(
+					opcode,
+					Fused::IncrementLocal {
+						slot: byte(1)?,
+						v: constant(idx)?,
+						idx,
+					},
+				)
+			}
+
+			OpCode::Return
+			| OpCode::Nil
 			| OpCode::False
 			| OpCode::True
 			| OpCode::Equal
@@ -180,11 +486,22 @@ impl Chunk {
 			| OpCode::Subtract
 			| OpCode::Multiply
 			| OpCode::Divide
+			| OpCode::FloorDivide
+			| OpCode::Modulo
+			| OpCode::FloorModulo
+			| OpCode::Power
 			| OpCode::Not
 			| OpCode::Print
+			| OpCode::PrintSep
 			| OpCode::Pop
-			| OpCode::Negate => Some(Ok(Instruction::simple(opcode))),
-		}
+			| OpCode::Dup
+			| OpCode::Swap
+			| OpCode::PopHandler
+			| OpCode::Throw
+			| OpCode::CheckCount
+			| OpCode::Negate => Instruction::simple(opcode),
+		};
+		Ok(instruction)
 	}
 
 	pub fn iter(&self) -> ChunkIter<'_> {
@@ -205,15 +522,25 @@ impl<'a> ChunkIter<'a> {
 	pub fn with_offset(self) -> ChunkWithOffsetIter<'a> {
 		ChunkWithOffsetIter { chunk_iter: self }
 	}
+
+	/// Like [ChunkIter::with_offset], with the source line of each instruction as well
+	pub fn with_lines(self) -> ChunkWithLinesIter<'a> {
+		ChunkWithLinesIter {
+			chunk_iter: self.with_offset(),
+		}
+	}
 }
 
 impl<'a> Iterator for ChunkIter<'a> {
-	type Item = Result<Instruction, UnknownOpCode>;
+	type Item = Result<Instruction, DecodeError>;
 
+	/// Stops after the first error, the offset of the next instruction isn't known then
 	fn next(&mut self) -> Option<Self::Item> {
 		let instruction = self.chunk.decode_instruction(self.offset);
-		if let Some(Ok(ref instruction)) = instruction {
-			self.offset += instruction.byte_len();
+		match &instruction {
+			Some(Ok(instruction)) => self.offset += instruction.byte_len(),
+			Some(Err(_)) => self.offset = self.chunk.code_len(),
+			None => (),
 		}
 		instruction
 	}
@@ -224,7 +551,7 @@ pub struct ChunkWithOffsetIter<'a> {
 }
 
 impl<'a> Iterator for ChunkWithOffsetIter<'a> {
-	type Item = Result<(Instruction, usize), UnknownOpCode>;
+	type Item = Result<(Instruction, usize), DecodeError>;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		let offset = self.chunk_iter.offset;
@@ -236,6 +563,20 @@ impl<'a> Iterator for ChunkWithOffsetIter<'a> {
 	}
 }
 
+pub struct ChunkWithLinesIter<'a> {
+	chunk_iter: ChunkWithOffsetIter<'a>,
+}
+
+impl<'a> Iterator for ChunkWithLinesIter<'a> {
+	type Item = Result<(Instruction, usize, usize), DecodeError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let chunk = self.chunk_iter.chunk_iter.chunk;
+		let item = self.chunk_iter.next()?;
+		Some(item.map(|(instruction, offset)| (instruction, offset, chunk.line(offset))))
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct Instruction {
 	pub kind: InstructionKind,
@@ -257,6 +598,35 @@ impl Instruction {
 		}
 	}
 
+	pub fn byte(opcode: OpCode, operand: u8) -> Self {
+		Instruction {
+			kind: InstructionKind::Byte { operand },
+			opcode,
+		}
+	}
+
+	pub fn short(opcode: OpCode, operand: u16) -> Self {
+		Instruction {
+			kind: InstructionKind::Short { operand },
+			opcode,
+		}
+	}
+
+	pub fn fused(opcode: OpCode, fused: Fused) -> Self {
+		Instruction {
+			kind: InstructionKind::Fused(fused),
+			opcode,
+		}
+	}
+
+	/// `target` is the absolute offset of the instruction to jump to
+	pub fn jump(opcode: OpCode, target: usize) -> Self {
+		Instruction {
+			kind: InstructionKind::Jump { target },
+			opcode,
+		}
+	}
+
 	pub fn byte_len(&self) -> usize {
 		self.kind.size()
 	}
@@ -264,10 +634,23 @@ impl Instruction {
 
 impl Display for Instruction {
 	fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-		write!(f, "{:<16} ", self.opcode)?;
+		write!(f, "{:<width$} ", self.opcode, width = OpCode::NAME_WIDTH)?;
 		match &self.kind {
 			InstructionKind::Simple => (),
 			InstructionKind::Constant { v, idx } => write!(f, "{idx:>4} '{v}'")?,
+			InstructionKind::Byte { operand } => write!(f, "{operand:>4}")?,
+			InstructionKind::Short { operand } => write!(f, "{operand:>4}")?,
+			InstructionKind::Jump { target } => write!(f, "-> {target}")?,
+			InstructionKind::Fused(Fused::LocalLocal { first, second }) => {
+				write!(f, "{first:>4} {second:>4}")?
+			}
+			InstructionKind::Fused(
+				Fused::LocalConstant { slot, v, idx } | Fused::IncrementLocal { slot, v, idx },
+			) => write!(f, "{slot:>4} {idx:>4} '{v}'")?,
+			InstructionKind::Fused(Fused::ConstantAdd { v, idx }) => write!(f, "{idx:>4} '{v}'")?,
+			InstructionKind::Fused(Fused::LocalNegate { slot } | Fused::LocalNot { slot }) => {
+				write!(f, "{slot:>4}")?
+			}
 		}
 		Ok(())
 	}
@@ -277,6 +660,21 @@ impl Display for Instruction {
 pub enum InstructionKind {
 	Simple,
 	Constant { v: Value, idx: usize },
+	Byte { operand: u8 },
+	Short { operand: u16 },
+	Jump { target: usize },
+	Fused(Fused),
+}
+
+/// Operands of superinstructions
+#[derive(Debug, Clone)]
+pub enum Fused {
+	LocalLocal { first: u8, second: u8 },
+	LocalConstant { slot: u8, v: Value, idx: usize },
+	ConstantAdd { v: Value, idx: usize },
+	LocalNegate { slot: u8 },
+	LocalNot { slot: u8 },
+	IncrementLocal { slot: u8, v: Value, idx: usize },
 }
 
 impl InstructionKind {
@@ -284,6 +682,14 @@ impl InstructionKind {
 		match self {
 			Self::Simple => 1,
 			Self::Constant { .. } => 2,
+			Self::Byte { .. } => 2,
+			Self::Short { .. } => 3,
+			Self::Jump { .. } => 3,
+			Self::Fused(Fused::LocalLocal { .. } | Fused::LocalConstant { .. }) => 4,
+			Self::Fused(
+				Fused::ConstantAdd { .. } | Fused::LocalNegate { .. } | Fused::LocalNot { .. },
+			) => 3,
+			Self::Fused(Fused::IncrementLocal { .. }) => 7,
 		}
 	}
 }
@@ -1,18 +1,146 @@
 use thiserror::Error;
 
 use crate::chunk::{Chunk, OpCode};
+use crate::globals::Globals;
 use crate::object;
-use crate::object::Object;
+use crate::object::{ObjString, Object};
 use crate::scanner::{self, Scanner, Token, TokenKind};
-use crate::value::Value;
+use crate::value::{Type, Value};
 
+/// Compiles `source` into `chunk`. On success returns warnings about code that is legal but
+/// likely a mistake.
 pub fn compile(
 	source: &str,
 	chunk: &mut Chunk,
 	debug: bool,
 	objects: &mut object::Allocator,
-) -> Result<(), Error> {
-	Compiler::new(source, chunk, debug, objects).compile()
+	globals: &mut Globals,
+) -> Result<Vec<Warning>, Error> {
+	compile_with_options(source, chunk, debug, objects, globals, Options::default())
+}
+
+/// Compiles `source` into a new chunk. Globals are resolved to slots in `globals`, so the chunk
+/// has to run with those globals, the ones of the VM that owns them. [Vm::compile] compiles with
+/// a VM's own allocator and globals.
+///
+/// [Vm::compile]: crate::vm::Vm::compile
+pub fn compile_to_chunk(
+	source: &str,
+	objects: &mut object::Allocator,
+	globals: &mut Globals,
+) -> Result<Chunk, Error> {
+	let mut chunk = Chunk::default();
+	compile(source, &mut chunk, false, objects, globals)?;
+	Ok(chunk)
+}
+
+/// How deeply statements and expressions can nest by default, see [Options::max_nesting]
+pub const DEFAULT_MAX_NESTING: usize = 512;
+
+/// Settings for [compile_with_options]
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+	/// Statements and expressions nesting deeper than this fail with [Error::NestingTooDeep].
+	/// The parser recurses for every level, so without a limit input like `((((...))))` can
+	/// overflow the stack.
+	pub max_nesting: usize,
+	/// Let a line break end a statement in place of `;`, so scripts can leave out most
+	/// semicolons. Experimental, a line starting with `(` or an operator still continues the
+	/// statement before it.
+	pub newline_terminators: bool,
+	/// Merge pops that follow each other into one `PopN`. On by default, turning it off is for
+	/// comparing against the unmerged code.
+	pub merge_pops: bool,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Options {
+			max_nesting: DEFAULT_MAX_NESTING,
+			newline_terminators: false,
+			merge_pops: true,
+		}
+	}
+}
+
+/// Like [compile], but fails with [Error::NestingTooDeep] when statements or expressions nest
+/// deeper than `max_nesting`, see [Options::max_nesting]
+pub fn compile_with_max_nesting(
+	source: &str,
+	chunk: &mut Chunk,
+	debug: bool,
+	objects: &mut object::Allocator,
+	globals: &mut Globals,
+	max_nesting: usize,
+) -> Result<Vec<Warning>, Error> {
+	let options = Options {
+		max_nesting,
+		..Options::default()
+	};
+	compile_with_options(source, chunk, debug, objects, globals, options)
+}
+
+/// Like [compile], with settings other than the defaults
+pub fn compile_with_options(
+	source: &str,
+	chunk: &mut Chunk,
+	debug: bool,
+	objects: &mut object::Allocator,
+	globals: &mut Globals,
+	options: Options,
+) -> Result<Vec<Warning>, Error> {
+	compile_located(source, chunk, debug, objects, globals, options).map_err(|(err, _)| err)
+}
+
+/// Like [compile_with_options], failing with the line the error was found on as well
+pub(crate) fn compile_located(
+	source: &str,
+	chunk: &mut Chunk,
+	debug: bool,
+	objects: &mut object::Allocator,
+	globals: &mut Globals,
+	options: Options,
+) -> Result<Vec<Warning>, (Error, usize)> {
+	let mut compiler = Compiler::new(source, chunk, debug, objects, globals);
+	compiler.options = options;
+	match compiler.compile() {
+		Ok(()) => Ok(compiler.warnings),
+		Err(err) => {
+			let line = compiler.error_line();
+			Err((err, line))
+		}
+	}
+}
+
+/// Parses `source` and renders its parse tree, one S-expression per top-level statement. For
+/// example `print 1 + 2 * 3;` becomes `(print (+ 1 (* 2 3)))`.
+pub fn parse_tree(source: &str) -> Result<String, Error> {
+	let mut chunk = Chunk::default();
+	let mut objects = object::Allocator::default();
+	let mut globals = Globals::default();
+	let mut compiler = Compiler::new(source, &mut chunk, false, &mut objects, &mut globals);
+	compiler.tree = Some(Vec::new());
+	compiler.advance()?;
+	while !compiler.matches(None)? {
+		compiler.declaration()?;
+	}
+	Ok(compiler.tree.unwrap_or_default().join("\n"))
+}
+
+#[derive(Debug, Error)]
+#[error("[line {line}] Warning: {kind}")]
+pub struct Warning {
+	pub kind: WarningKind,
+	pub line: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum WarningKind {
+	#[error("Assignment used as a condition, did you mean '=='? Wrap it in parentheses if not")]
+	AssignmentInCondition,
+
+	#[error("Unreachable code after '{keyword}'")]
+	UnreachableCode { keyword: &'static str },
 }
 
 #[derive(Debug, Error)]
@@ -40,6 +168,65 @@ pub enum Error {
 
 	#[error("Expected variable name")]
 	ExpectedVariableName,
+
+	#[error("Too much code to jump over")]
+	TooMuchCodeToJumpOver,
+
+	#[error("Too many local variables in function")]
+	TooManyLocals,
+
+	#[error("Can't read local variable in its own initializer")]
+	ReadLocalInOwnInitializer,
+
+	#[error("Already a variable named '{name}' in this scope")]
+	DuplicateDeclaration { name: String },
+
+	#[error(transparent)]
+	AllocationLimitExceeded(#[from] object::AllocationLimitExceeded),
+
+	#[error("Expected a literal pattern")]
+	ExpectedPattern,
+
+	#[error("Expected a '_' arm at the end of match")]
+	ExpectedWildcardArm,
+
+	#[error("Expected a string with the path to import")]
+	ExpectedImportPath,
+
+	#[error("Invalid assignment target")]
+	InvalidAssignmentTarget,
+
+	#[error("Too many global variables")]
+	TooManyGlobals,
+
+	#[error("Can't have more than 255 arguments")]
+	TooManyArguments,
+
+	#[error("Loop body too large")]
+	LoopBodyTooLarge,
+
+	#[error("Can't use '{keyword}' outside of a loop")]
+	OutsideLoop { keyword: &'static str },
+
+	#[error("No enclosing loop labeled '{label}'")]
+	UnknownLabel { label: String },
+
+	#[error("Expected a loop after label")]
+	ExpectedLoopAfterLabel,
+
+	#[error("Can't use 'this' outside of a class")]
+	ThisOutsideClass,
+
+	#[error("Expected a type name after 'is'")]
+	ExpectedTypeName,
+
+	#[error(
+		"Unknown type '{name}', expected one of Nil, Bool, Number, String, Function or Foreign"
+	)]
+	UnknownType { name: String },
+
+	#[error("Nesting is deeper than the limit of {limit} levels")]
+	NestingTooDeep { limit: usize },
 }
 
 struct Compiler<'a, 'b, 'c> {
@@ -51,8 +238,70 @@ struct Compiler<'a, 'b, 'c> {
 
 	parser_had_error: bool,
 	parser_panic_mode: bool,
+	/// Line of the first scanner error, which is reported as [Error::ParserError]
+	scanner_error_line: Option<usize>,
 
 	objects: &'c mut object::Allocator,
+	globals: &'c mut Globals,
+
+	locals: Vec<Local<'a>>,
+	scope_depth: usize,
+
+	/// Whether the expression being parsed can be the target of an assignment, i.e. it isn't an
+	/// operand of an operator that binds tighter than `=`
+	can_assign: bool,
+
+	/// Offset of the latest instruction a jump lands on. Pops before it can't be merged with pops
+	/// after it, the jump would skip only some of them.
+	jump_target: usize,
+	/// Offset of the `Pop` or `PopN` at the end of the code, see [Compiler::emit_pops]
+	trailing_pops: Option<usize>,
+
+	/// Offset of the `Constant` instruction of the latest number literal, see
+	/// [Compiler::fold_negation]
+	number_literal: Option<usize>,
+
+	/// Nesting levels of the statements and expressions being parsed, each one inside the previous,
+	/// see [Compiler::nested]
+	nesting: usize,
+
+	options: Options,
+
+	/// Innermost loop last
+	loops: Vec<Loop<'a>>,
+	/// Label in front of the loop statement being compiled, taken by [Compiler::loop_body]
+	pending_label: Option<&'a str>,
+	/// Number of exception handlers the code being compiled runs under
+	handler_depth: usize,
+
+	/// Code offset right after the latest assignment, for spotting conditions that are one
+	assignment_end: Option<usize>,
+	/// Code offset right after the latest parenthesized expression, a condition ending at both
+	/// this and [Compiler::assignment_end] is a whole assignment wrapped in parentheses
+	grouping_end: Option<usize>,
+	warnings: Vec<Warning>,
+
+	/// Rendered nodes of the parse tree when it's requested through [parse_tree]. Each parse
+	/// function pushes its node, wrapping the nodes its children pushed.
+	tree: Option<Vec<String>>,
+}
+
+struct Local<'a> {
+	name: &'a str,
+	/// `None` until the variable's initializer has been compiled
+	depth: Option<usize>,
+}
+
+struct Loop<'a> {
+	/// Name given with `label:` before the loop, for `break label;` and `continue label;`
+	label: Option<&'a str>,
+	scope_depth: usize,
+	handler_depth: usize,
+	/// Where `continue` jumps back to, `None` if the target comes after the body and the jumps
+	/// have to be patched later
+	continue_target: Option<usize>,
+	continue_jumps: Vec<usize>,
+	break_jumps: Vec<usize>,
 }
 
 struct ParseRule<'a, 'b, 'c> {
@@ -61,12 +310,13 @@ struct ParseRule<'a, 'b, 'c> {
 	precedence: Precedence,
 }
 
-#[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u32)]
 enum Precedence {
 	None = 0,
 	Assignment,
+	Conditional,
+	Coalesce,
 	Or,
 	And,
 	Equality,
@@ -74,10 +324,34 @@ enum Precedence {
 	Term,
 	Factor,
 	Unary,
+	Exponent,
 	Call,
 	Primary,
 }
 
+impl Precedence {
+	/// The next higher precedence, the right operand of a left-associative operator is parsed
+	/// with it. Saturates at [Precedence::Primary].
+	fn next(self) -> Precedence {
+		match self {
+			Precedence::None => Precedence::Assignment,
+			Precedence::Assignment => Precedence::Conditional,
+			Precedence::Conditional => Precedence::Coalesce,
+			Precedence::Coalesce => Precedence::Or,
+			Precedence::Or => Precedence::And,
+			Precedence::And => Precedence::Equality,
+			Precedence::Equality => Precedence::Comparison,
+			Precedence::Comparison => Precedence::Term,
+			Precedence::Term => Precedence::Factor,
+			Precedence::Factor => Precedence::Unary,
+			Precedence::Unary => Precedence::Exponent,
+			Precedence::Exponent => Precedence::Call,
+			Precedence::Call => Precedence::Primary,
+			Precedence::Primary => Precedence::Primary,
+		}
+	}
+}
+
 type ParseFn<'a, 'b, 'c> = fn(&mut Compiler<'a, 'b, 'c>) -> Result<(), Error>;
 
 impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
@@ -86,6 +360,7 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		chunk: &'b mut Chunk,
 		debug: bool,
 		objects: &'c mut object::Allocator,
+		globals: &'c mut Globals,
 	) -> Self {
 		Compiler {
 			scanner: Scanner::new(source),
@@ -97,12 +372,38 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			},
 			parser_had_error: false,
 			parser_panic_mode: false,
+			scanner_error_line: None,
 
 			objects,
+			globals,
+
+			locals: Vec::new(),
+			scope_depth: 0,
+
+			can_assign: false,
+
+			jump_target: 0,
+			trailing_pops: None,
+
+			number_literal: None,
+
+			nesting: 0,
+
+			options: Options::default(),
+
+			loops: Vec::new(),
+			pending_label: None,
+			handler_depth: 0,
+
+			assignment_end: None,
+			grouping_end: None,
+			warnings: Vec::new(),
+
+			tree: None,
 		}
 	}
 
-	pub fn compile(mut self) -> Result<(), Error> {
+	pub fn compile(&mut self) -> Result<(), Error> {
 		self.parser_had_error = false;
 		self.parser_panic_mode = false;
 
@@ -117,6 +418,19 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		Ok(())
 	}
 
+	/// Line the latest error was found on. That's the line of the last token the parser consumed,
+	/// e.g. the value missing a `;` after it, unless the scanner failed first.
+	fn error_line(&self) -> usize {
+		if let Some(line) = self.scanner_error_line {
+			return line;
+		}
+		self.parser
+			.previous
+			.as_ref()
+			.or(self.parser.current.as_ref())
+			.map_or(self.scanner.line, |token| token.line)
+	}
+
 	fn advance(&mut self) -> Result<(), Error> {
 		self.parser.previous = self.parser.current.clone();
 
@@ -136,6 +450,7 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 					}
 					self.parser_panic_mode = true;
 					self.parser_had_error = true;
+					self.scanner_error_line.get_or_insert(err.line());
 				}
 			};
 		}
@@ -164,6 +479,30 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		Ok(true)
 	}
 
+	/// Consumes the `;` ending a statement. It's optional after the last statement of the source,
+	/// so `print 1` alone compiles but `print 1 print 2` doesn't. With
+	/// [Options::newline_terminators] a line break or the `}` closing a block can take its place
+	/// too.
+	fn consume_semicolon(&mut self, after: &'static str) -> Result<(), Error> {
+		if self.matches(Some(TokenKind::Semicolon))? || self.check(None) {
+			return Ok(());
+		}
+		if self.options.newline_terminators
+			&& (self.at_line_start() || self.check(Some(TokenKind::RightBrace)))
+		{
+			return Ok(());
+		}
+		Err(Error::ExpectedToken { token: ";", after })
+	}
+
+	/// Whether the current token is on a later line than the previous one
+	fn at_line_start(&self) -> bool {
+		match (&self.parser.previous, &self.parser.current) {
+			(Some(previous), Some(current)) => current.line > previous.line,
+			_ => false,
+		}
+	}
+
 	fn check(&mut self, kind: Option<TokenKind>) -> bool {
 		self.parser.current.as_ref().map(|token| token.kind) == kind
 	}
@@ -178,7 +517,7 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			.previous
 			.as_ref()
 			.map(|token| token.line)
-			.unwrap_or(0);
+			.unwrap_or(self.scanner.line);
 		self.current_chunk().write(byte, line);
 	}
 
@@ -188,6 +527,38 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		}
 	}
 
+	/// Emits a jump instruction with a placeholder operand and returns the operand's offset, to be
+	/// filled in later by [Compiler::patch_jump].
+	fn emit_jump(&mut self, instruction: OpCode) -> usize {
+		self.emit_bytes([instruction as u8, 0xff, 0xff]);
+		self.chunk.code_len() - 2
+	}
+
+	fn patch_jump(&mut self, offset: usize) -> Result<(), Error> {
+		// -2 to adjust for the jump operand itself
+		let jump = self.mark_jump_target() - offset - 2;
+		let jump = u16::try_from(jump).map_err(|_| Error::TooMuchCodeToJumpOver)?;
+		self.chunk.patch_jump(offset, jump);
+		Ok(())
+	}
+
+	/// Offset of the next instruction, which a jump will land on
+	fn mark_jump_target(&mut self) -> usize {
+		self.jump_target = self.chunk.code_len();
+		self.jump_target
+	}
+
+	/// Emits a jump backwards to `loop_start`
+	fn emit_loop(&mut self, loop_start: usize) -> Result<(), Error> {
+		self.emit_byte(OpCode::Loop as u8);
+		// +2 to adjust for the jump operand itself
+		let jump = self.chunk.code_len() - loop_start + 2;
+		let jump = u16::try_from(jump).map_err(|_| Error::LoopBodyTooLarge)?;
+		let [hi, lo] = jump.to_be_bytes();
+		self.emit_bytes([hi, lo]);
+		Ok(())
+	}
+
 	fn emit_return(&mut self) {
 		self.emit_byte(OpCode::Return as u8);
 	}
@@ -205,6 +576,64 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 
 	fn end_compiler(&mut self) {
 		self.emit_return();
+		if self.debug {
+			println!("{}", self.chunk.disassemble("code"));
+		}
+	}
+
+	fn begin_scope(&mut self) {
+		self.scope_depth += 1;
+	}
+
+	fn end_scope(&mut self) {
+		self.scope_depth -= 1;
+
+		let mut popped = 0;
+		while let Some(local) = self.locals.last() {
+			if local.depth.is_some_and(|depth| depth <= self.scope_depth) {
+				break;
+			}
+			self.locals.pop();
+			popped += 1;
+		}
+
+		self.emit_pops(popped);
+	}
+
+	/// Emits pops of `count` values. They are merged into the pops right before them when nothing
+	/// runs in between, e.g. the pop of the last expression statement in a block and the pops of
+	/// the block's locals. A block can have more locals than one `PopN` pops, those take several.
+	fn emit_pops(&mut self, count: usize) {
+		let mut count = count;
+		if let Some((offset, previous)) = self.trailing_pops() {
+			if count > 0 && count + previous as usize <= u8::MAX as usize {
+				self.chunk.truncate(offset);
+				count += previous as usize;
+			}
+		}
+
+		while count > 0 {
+			let n = count.min(u8::MAX as usize) as u8;
+			self.trailing_pops = Some(self.chunk.code_len());
+			match n {
+				1 => self.emit_byte(OpCode::Pop as u8),
+				n => self.emit_bytes([OpCode::PopN as u8, n]),
+			}
+			count -= n as usize;
+		}
+	}
+
+	/// Offset and count of the pops at the end of the code, if more pops can be merged into them
+	fn trailing_pops(&self) -> Option<(usize, u8)> {
+		let offset = self.trailing_pops?;
+		if !self.options.merge_pops || offset < self.jump_target {
+			return None;
+		}
+		match self.chunk.code()[offset..] {
+			[op] if op == OpCode::Pop as u8 => Some((offset, 1)),
+			[op, n] if op == OpCode::PopN as u8 => Some((offset, n)),
+			_ => None,
+		}
 	}
 
 	fn synchronize(&mut self) -> Result<(), Error> {
@@ -222,6 +651,8 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				| TokenKind::For
 				| TokenKind::If
 				| TokenKind::While
+				| TokenKind::Do
+				| TokenKind::Repeat
 				| TokenKind::Print
 				| TokenKind::Return => return Ok(()),
 				_ => (),
@@ -239,7 +670,7 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 
 	fn declaration(&mut self) -> Result<(), Error> {
 		let result = if self.matches(Some(TokenKind::Var))? {
-			self.var_declaration()
+			self.tree_statement("var", Self::var_declaration)
 		} else {
 			self.statement()
 		};
@@ -254,6 +685,13 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 
 	fn var_declaration(&mut self) -> Result<(), Error> {
 		let global = self.parse_variable(Error::ExpectedVariableName)?;
+		if let Some(Token {
+			kind: TokenKind::Identifier(name),
+			..
+		}) = self.parser.previous
+		{
+			self.tree_leaf(name);
+		}
 
 		if self.matches(Some(TokenKind::Equal))? {
 			self.expression()?;
@@ -261,13 +699,7 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			self.emit_byte(OpCode::Nil as u8);
 		}
 
-		self.consume(
-			Some(TokenKind::Semicolon),
-			Error::ExpectedToken {
-				token: ";",
-				after: "variable declaration",
-			},
-		)?;
+		self.consume_semicolon("variable declaration")?;
 
 		self.define_variable(global);
 
@@ -275,35 +707,581 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 	}
 
 	fn statement(&mut self) -> Result<(), Error> {
+		// Compiling a statement takes about twice the stack of an expression, so it counts as two
+		// levels
+		self.nested(2, Self::statement_unchecked)
+	}
+
+	/// [Compiler::statement] without the nesting limit
+	fn statement_unchecked(&mut self) -> Result<(), Error> {
 		if self.matches(Some(TokenKind::Print))? {
-			return self.print_statement();
+			return self.tree_statement("print", Self::print_statement);
+		}
+		if self.matches(Some(TokenKind::Match))? {
+			return self.tree_statement("match", Self::match_statement);
+		}
+		if self.matches(Some(TokenKind::Try))? {
+			return self.tree_statement("try", Self::try_statement);
+		}
+		if self.matches(Some(TokenKind::Throw))? {
+			return self.tree_statement("throw", Self::throw_statement);
+		}
+		if self.matches(Some(TokenKind::Import))? {
+			return self.tree_statement("import", Self::import_statement);
+		}
+		if self.matches(Some(TokenKind::While))? {
+			return self.tree_statement("while", Self::while_statement);
+		}
+		if self.matches(Some(TokenKind::Do))? {
+			return self.tree_statement("do", Self::do_statement);
+		}
+		if self.matches(Some(TokenKind::Repeat))? {
+			return self.tree_statement("repeat", |compiler| {
+				compiler.begin_scope();
+				let result = compiler.repeat_statement();
+				compiler.end_scope();
+				result
+			});
+		}
+		if self.matches(Some(TokenKind::Break))? {
+			return self.tree_statement("break", Self::break_statement);
+		}
+		if self.matches(Some(TokenKind::Continue))? {
+			return self.tree_statement("continue", Self::continue_statement);
+		}
+		if let Some(label) = self.label()? {
+			if !matches!(
+				self.parser.current.as_ref().map(|token| token.kind),
+				Some(TokenKind::While | TokenKind::Do | TokenKind::Repeat)
+			) {
+				return Err(Error::ExpectedLoopAfterLabel);
+			}
+			self.pending_label = Some(label);
+			return self.statement_unchecked();
+		}
+		if self.matches(Some(TokenKind::LeftBrace))? {
+			return self.tree_statement("block", |compiler| {
+				compiler.begin_scope();
+				let result = compiler.block();
+				compiler.end_scope();
+				result
+			});
 		}
 		self.expression_statement()
 	}
 
-	fn print_statement(&mut self) -> Result<(), Error> {
+	/// `match subject { pattern => statement ... _ => statement }`
+	///
+	/// Patterns are literals compared against the subject with `==`, arms are tried top to bottom
+	/// and the first one that matches runs. The `_` arm is mandatory and has to be the last one.
+	fn match_statement(&mut self) -> Result<(), Error> {
 		self.expression()?;
 		self.consume(
-			Some(TokenKind::Semicolon),
+			Some(TokenKind::LeftBrace),
 			Error::ExpectedToken {
-				token: ";",
-				after: "value",
+				token: "{",
+				after: "match subject",
 			},
 		)?;
-		self.emit_byte(OpCode::Print as u8);
+
+		let mut end_jumps = Vec::new();
+		loop {
+			if self.matches(Some(TokenKind::Identifier("_")))? {
+				self.consume(
+					Some(TokenKind::EqualGreater),
+					Error::ExpectedToken {
+						token: "=>",
+						after: "pattern",
+					},
+				)?;
+				self.emit_byte(OpCode::Pop as u8);
+				self.statement()?;
+				break;
+			}
+			if self.check(Some(TokenKind::RightBrace)) || self.check(None) {
+				return Err(Error::ExpectedWildcardArm);
+			}
+
+			self.emit_byte(OpCode::Dup as u8);
+			self.pattern()?;
+			self.emit_byte(OpCode::Equal as u8);
+			self.consume(
+				Some(TokenKind::EqualGreater),
+				Error::ExpectedToken {
+					token: "=>",
+					after: "pattern",
+				},
+			)?;
+
+			let next_arm = self.emit_jump(OpCode::JumpIfFalse);
+			self.emit_bytes([OpCode::Pop as u8, OpCode::Pop as u8]);
+			self.statement()?;
+			end_jumps.push(self.emit_jump(OpCode::Jump));
+
+			self.patch_jump(next_arm)?;
+			self.emit_byte(OpCode::Pop as u8);
+		}
+
+		self.consume(
+			Some(TokenKind::RightBrace),
+			Error::ExpectedToken {
+				token: "}",
+				after: "match arms",
+			},
+		)?;
+
+		for end_jump in end_jumps {
+			self.patch_jump(end_jump)?;
+		}
 		Ok(())
 	}
 
-	fn expression_statement(&mut self) -> Result<(), Error> {
+	/// `try { ... } catch (name) { ... }`
+	///
+	/// The try block runs with an exception handler installed. A `throw` inside it unwinds the
+	/// stack back to where it was when the handler was installed, and continues in the catch block
+	/// with the thrown value bound to `name`.
+	fn try_statement(&mut self) -> Result<(), Error> {
+		self.consume(
+			Some(TokenKind::LeftBrace),
+			Error::ExpectedToken {
+				token: "{",
+				after: "'try'",
+			},
+		)?;
+		let handler = self.emit_jump(OpCode::PushHandler);
+		self.handler_depth += 1;
+		self.begin_scope();
+		let result = self.block();
+		self.end_scope();
+		self.handler_depth -= 1;
+		result?;
+		self.emit_byte(OpCode::PopHandler as u8);
+		let end_jump = self.emit_jump(OpCode::Jump);
+
+		self.patch_jump(handler)?;
+		self.consume(
+			Some(TokenKind::Catch),
+			Error::ExpectedToken {
+				token: "catch",
+				after: "try block",
+			},
+		)?;
+		self.consume(
+			Some(TokenKind::LeftParen),
+			Error::ExpectedToken {
+				token: "(",
+				after: "'catch'",
+			},
+		)?;
+		let name = match self.parser.current.as_ref() {
+			Some(Token {
+				kind: TokenKind::Identifier(ident),
+				..
+			}) => *ident,
+			_ => return Err(Error::ExpectedVariableName),
+		};
+		self.advance()?;
+		self.consume(
+			Some(TokenKind::RightParen),
+			Error::ExpectedToken {
+				token: ")",
+				after: "catch variable",
+			},
+		)?;
+		self.consume(
+			Some(TokenKind::LeftBrace),
+			Error::ExpectedToken {
+				token: "{",
+				after: "catch variable",
+			},
+		)?;
+
+		self.tree_leaf(name);
+		// The VM leaves the thrown value on top of the stack, which makes it the catch variable's slot
+		self.begin_scope();
+		let result = self.add_local(name).and_then(|_| {
+			self.mark_initialized();
+			self.block()
+		});
+		self.end_scope();
+		result?;
+
+		self.patch_jump(end_jump)
+	}
+
+	fn throw_statement(&mut self) -> Result<(), Error> {
+		self.expression()?;
+		self.consume_semicolon("thrown value")?;
+		self.emit_byte(OpCode::Throw as u8);
+		Ok(())
+	}
+
+	fn while_statement(&mut self) -> Result<(), Error> {
+		let loop_start = self.mark_jump_target();
+		self.condition()?;
+
+		let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+		self.emit_byte(OpCode::Pop as u8);
+		let body = self.loop_body(Some(loop_start))?;
+		self.emit_loop(loop_start)?;
+
+		self.patch_jump(exit_jump)?;
+		self.emit_byte(OpCode::Pop as u8);
+		for break_jump in body.break_jumps {
+			self.patch_jump(break_jump)?;
+		}
+		Ok(())
+	}
+
+	/// `repeat count { ... }` runs the block `count` times. The count is evaluated once into a
+	/// hidden local and has to be a non-negative whole number, anything else is a runtime error.
+	fn repeat_statement(&mut self) -> Result<(), Error> {
 		self.expression()?;
+		self.emit_byte(OpCode::CheckCount as u8);
+		// Not a valid identifier, so scripts can't refer to it
+		self.add_local("repeat count")?;
+		self.mark_initialized();
+		let slot = (self.locals.len() - 1) as u8;
+		if !self.check(Some(TokenKind::LeftBrace)) {
+			return Err(Error::ExpectedToken {
+				token: "{",
+				after: "repeat count",
+			});
+		}
+
+		// The count is decremented before the body runs, so `continue` can jump straight back here
+		let loop_start = self.mark_jump_target();
+		self.emit_bytes([OpCode::GetLocal as u8, slot]);
+		self.emit_constant(Value::Number(0.0))?;
+		self.emit_byte(OpCode::Greater as u8);
+		let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+		self.emit_byte(OpCode::Pop as u8);
+		self.emit_bytes([OpCode::GetLocal as u8, slot]);
+		self.emit_constant(Value::Number(1.0))?;
+		self.emit_bytes([OpCode::Subtract as u8, OpCode::SetLocal as u8, slot]);
+		self.emit_byte(OpCode::Pop as u8);
+
+		let body = self.loop_body(Some(loop_start))?;
+		self.emit_loop(loop_start)?;
+
+		self.patch_jump(exit_jump)?;
+		self.emit_byte(OpCode::Pop as u8);
+		for break_jump in body.break_jumps {
+			self.patch_jump(break_jump)?;
+		}
+		Ok(())
+	}
+
+	/// Parenthesized loop condition. A bare assignment like `while (x = 1)` compiles, but with a
+	/// warning since `==` was probably meant. `while ((x = 1))` silences it.
+	fn condition(&mut self) -> Result<(), Error> {
 		self.consume(
-			Some(TokenKind::Semicolon),
+			Some(TokenKind::LeftParen),
 			Error::ExpectedToken {
-				token: ";",
-				after: "expression",
+				token: "(",
+				after: "'while'",
+			},
+		)?;
+		self.expression()?;
+		let end = Some(self.chunk.code_len());
+		if self.assignment_end == end && self.grouping_end != end {
+			self.warn(WarningKind::AssignmentInCondition);
+		}
+		self.consume(
+			Some(TokenKind::RightParen),
+			Error::ExpectedToken {
+				token: ")",
+				after: "condition",
+			},
+		)
+	}
+
+	fn warn(&mut self, kind: WarningKind) {
+		let line = self
+			.parser
+			.previous
+			.as_ref()
+			.map(|token| token.line)
+			.unwrap_or(self.scanner.line);
+		self.warnings.push(Warning { kind, line });
+	}
+
+	fn tree_len(&self) -> usize {
+		self.tree.as_ref().map_or(0, Vec::len)
+	}
+
+	fn tree_leaf(&mut self, leaf: impl std::fmt::Display) {
+		if let Some(tree) = &mut self.tree {
+			tree.push(leaf.to_string());
+		}
+	}
+
+	/// Replaces the last `children` nodes with a `(head children...)` node
+	fn tree_node(&mut self, head: &str, children: usize) {
+		if let Some(tree) = &mut self.tree {
+			let children = tree.split_off(tree.len() - children);
+			let mut node = format!("({head}");
+			for child in children {
+				node.push(' ');
+				node.push_str(&child);
+			}
+			node.push(')');
+			tree.push(node);
+		}
+	}
+
+	/// Compiles a statement with `compile` and groups the nodes it adds under `head`
+	fn tree_statement(
+		&mut self,
+		head: &str,
+		compile: fn(&mut Self) -> Result<(), Error>,
+	) -> Result<(), Error> {
+		let mark = self.tree_len();
+		compile(self)?;
+		self.tree_node(head, self.tree_len() - mark);
+		Ok(())
+	}
+
+	/// `do statement while (condition);` runs the body at least once, the condition is checked
+	/// after each iteration. `continue` skips to the condition.
+	fn do_statement(&mut self) -> Result<(), Error> {
+		let loop_start = self.mark_jump_target();
+		let body = self.loop_body(None)?;
+		for continue_jump in body.continue_jumps {
+			self.patch_jump(continue_jump)?;
+		}
+
+		self.consume(
+			Some(TokenKind::While),
+			Error::ExpectedToken {
+				token: "while",
+				after: "do body",
 			},
 		)?;
+		self.condition()?;
+		self.consume_semicolon("do-while condition")?;
+
+		let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+		self.emit_byte(OpCode::Pop as u8);
+		self.emit_loop(loop_start)?;
+
+		self.patch_jump(exit_jump)?;
 		self.emit_byte(OpCode::Pop as u8);
+		for break_jump in body.break_jumps {
+			self.patch_jump(break_jump)?;
+		}
+		Ok(())
+	}
+
+	/// Consumes a `label:` in front of a loop. An identifier followed by `:` can't start an
+	/// expression, so a token of lookahead tells the two apart.
+	fn label(&mut self) -> Result<Option<&'a str>, Error> {
+		let Some(TokenKind::Identifier(label)) = self.parser.current.as_ref().map(|t| t.kind)
+		else {
+			return Ok(None);
+		};
+		let next = self.scanner.clone().scan_token();
+		if !matches!(
+			next,
+			Some(Ok(Token {
+				kind: TokenKind::Colon,
+				..
+			}))
+		) {
+			return Ok(None);
+		}
+		self.advance()?;
+		self.advance()?;
+		Ok(Some(label))
+	}
+
+	/// Compiles the body of a loop and returns its `break` and `continue` jumps that still need to
+	/// be patched. The caller must patch the breaks after popping the loop condition.
+	fn loop_body(&mut self, continue_target: Option<usize>) -> Result<Loop<'a>, Error> {
+		self.loops.push(Loop {
+			label: self.pending_label.take(),
+			scope_depth: self.scope_depth,
+			handler_depth: self.handler_depth,
+			continue_target,
+			continue_jumps: Vec::new(),
+			break_jumps: Vec::new(),
+		});
+		let result = self.statement();
+		let body = self.loops.pop().expect("loop stack out of sync");
+		result.map(|_| body)
+	}
+
+	/// `break;` or `break label;`
+	fn break_statement(&mut self) -> Result<(), Error> {
+		let target = self.jump_target_loop("break")?;
+		self.consume_semicolon("'break'")?;
+		self.exit_loop_body(target);
+		let jump = self.emit_jump(OpCode::Jump);
+		self.loops[target].break_jumps.push(jump);
+		Ok(())
+	}
+
+	/// `continue;` or `continue label;`
+	fn continue_statement(&mut self) -> Result<(), Error> {
+		let target = self.jump_target_loop("continue")?;
+		self.consume_semicolon("'continue'")?;
+		self.exit_loop_body(target);
+		match self.loops[target].continue_target {
+			Some(continue_target) => self.emit_loop(continue_target)?,
+			None => {
+				let jump = self.emit_jump(OpCode::Jump);
+				self.loops[target].continue_jumps.push(jump);
+			}
+		}
+		Ok(())
+	}
+
+	/// Index in [Compiler::loops] of the loop `break` or `continue` applies to: the one named by
+	/// the label following the keyword, or the innermost one
+	fn jump_target_loop(&mut self, keyword: &'static str) -> Result<usize, Error> {
+		if self.loops.is_empty() {
+			return Err(Error::OutsideLoop { keyword });
+		}
+		let innermost = self.loops.len() - 1;
+		let Some(TokenKind::Identifier(label)) = self.parser.current.as_ref().map(|t| t.kind)
+		else {
+			return Ok(innermost);
+		};
+		// With line breaks ending statements, an identifier on the next line starts a new one
+		if self.options.newline_terminators && self.at_line_start() {
+			return Ok(innermost);
+		}
+		self.advance()?;
+		self.tree_leaf(label);
+		self.loops
+			.iter()
+			.rposition(|l| l.label == Some(label))
+			.ok_or_else(|| Error::UnknownLabel {
+				label: label.to_string(),
+			})
+	}
+
+	/// Pops the locals and exception handlers of the body of the loop at `target` in
+	/// [Compiler::loops], before jumping out of it
+	fn exit_loop_body(&mut self, target: usize) {
+		let target = &self.loops[target];
+		let handlers = self.handler_depth - target.handler_depth;
+		let locals = self
+			.locals
+			.iter()
+			.rev()
+			.take_while(|local| local.depth.is_some_and(|depth| depth > target.scope_depth))
+			.count();
+
+		for _ in 0..handlers {
+			self.emit_byte(OpCode::PopHandler as u8);
+		}
+		self.emit_pops(locals);
+	}
+
+	/// `import "path";` runs another file in the same VM, see [crate::vm::Vm::interpret_file]
+	fn import_statement(&mut self) -> Result<(), Error> {
+		let path = match self.parser.current.as_ref() {
+			Some(Token {
+				kind: TokenKind::String(path),
+				..
+			}) => *path,
+			_ => return Err(Error::ExpectedImportPath),
+		};
+		self.advance()?;
+		self.consume_semicolon("import path")?;
+
+		self.tree_leaf(format_args!("\"{path}\""));
+		let path = self.objects.copy_string(path)?;
+		let path = self.make_constant(Value::Object(path))?;
+		self.emit_bytes([OpCode::Import as u8, path]);
+		Ok(())
+	}
+
+	fn pattern(&mut self) -> Result<(), Error> {
+		let negate = self.matches(Some(TokenKind::Minus))?;
+		let kind = self.parser.current.as_ref().map(|token| token.kind);
+		match kind {
+			Some(TokenKind::Number(_)) => {
+				self.advance()?;
+				self.number()?;
+				if negate {
+					self.emit_byte(OpCode::Negate as u8);
+					self.tree_node("-", 1);
+				}
+				Ok(())
+			}
+			Some(TokenKind::String(_)) if !negate => {
+				self.advance()?;
+				self.string()
+			}
+			Some(TokenKind::True | TokenKind::False | TokenKind::Nil) if !negate => {
+				self.advance()?;
+				self.literal()
+			}
+			_ => Err(Error::ExpectedPattern),
+		}
+	}
+
+	/// Statements following a `break`, `continue` or `throw` in the same block never run, the
+	/// first of them gets a warning
+	fn block(&mut self) -> Result<(), Error> {
+		let mut terminated_by = None;
+		let mut warned = false;
+		while !self.check(Some(TokenKind::RightBrace)) && !self.check(None) {
+			if let Some(keyword) = terminated_by.filter(|_| !warned) {
+				let line = self
+					.parser
+					.current
+					.as_ref()
+					.map_or(self.scanner.line, |t| t.line);
+				self.warnings.push(Warning {
+					kind: WarningKind::UnreachableCode { keyword },
+					line,
+				});
+				warned = true;
+			}
+			terminated_by = match self.parser.current.as_ref().map(|token| token.kind) {
+				Some(TokenKind::Break) => Some("break"),
+				Some(TokenKind::Continue) => Some("continue"),
+				Some(TokenKind::Throw) => Some("throw"),
+				_ => None,
+			};
+			self.declaration()?;
+		}
+
+		self.consume(
+			Some(TokenKind::RightBrace),
+			Error::ExpectedToken {
+				token: "}",
+				after: "block",
+			},
+		)
+	}
+
+	/// `print a, b;` prints the values separated by spaces
+	fn print_statement(&mut self) -> Result<(), Error> {
+		self.expression()?;
+		while self.matches(Some(TokenKind::Comma))? {
+			self.emit_bytes([OpCode::Print as u8, OpCode::PrintSep as u8]);
+			self.expression()?;
+		}
+		self.consume_semicolon("value")?;
+		self.emit_byte(OpCode::Print as u8);
+		Ok(())
+	}
+
+	/// The value of an expression statement that ends the script is left on the stack for `Return`
+	/// to hand back to [Vm::interpret](crate::vm::Vm::interpret). Its semicolon is optional.
+	fn expression_statement(&mut self) -> Result<(), Error> {
+		self.expression()?;
+		let top_level = self.scope_depth == 0 && self.loops.is_empty();
+		self.consume_semicolon("expression")?;
+		if top_level && self.check(None) {
+			return Ok(());
+		}
+		self.emit_pops(1);
 		Ok(())
 	}
 
@@ -311,7 +1289,9 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		let TokenKind::Number(num) = self.parser.previous.as_ref().unwrap().kind else {
 			panic!("expected number");
 		};
+		self.tree_leaf(num);
 		let num: f64 = num.parse().unwrap();
+		self.number_literal = Some(self.chunk.code_len());
 		self.emit_constant(Value::Number(num))?;
 		Ok(())
 	}
@@ -320,7 +1300,8 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 		let TokenKind::String(str) = self.parser.previous.as_ref().unwrap().kind else {
 			panic!("expected string");
 		};
-		let object = self.objects.copy_string(str);
+		self.tree_leaf(format_args!("\"{str}\""));
+		let object = self.objects.copy_string(str)?;
 		self.emit_constant(Value::Object(object))?;
 		Ok(())
 	}
@@ -334,31 +1315,58 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				after: "expression",
 			},
 		)?;
+		self.grouping_end = Some(self.chunk.code_len());
+		self.tree_node("group", 1);
 		Ok(())
 	}
 
 	fn unary(&mut self) -> Result<(), Error> {
 		let op_kind = self.parser.previous.as_ref().unwrap().kind;
+		let operand_start = self.chunk.code_len();
 		self.parse_precedence(Precedence::Unary)?;
 		match op_kind {
+			TokenKind::Minus if self.fold_negation(operand_start) => (),
 			TokenKind::Minus => self.emit_byte(OpCode::Negate as u8),
 			TokenKind::Bang => self.emit_byte(OpCode::Not as u8),
 			_ => unreachable!(),
 		}
+		self.tree_node(operator_symbol(op_kind), 1);
 		Ok(())
 	}
 
+	/// Negates the constant of a number literal that's the whole operand of `-`, so `-5` is a
+	/// single `Constant`. The operand's code starts at `operand_start`, it's only the literal if
+	/// that's where the literal is and nothing follows it. `-(c ? 1 : 2)` also ends with a literal
+	/// but can't be folded. Returns whether it did.
+	fn fold_negation(&mut self, operand_start: usize) -> bool {
+		if self.number_literal != Some(operand_start)
+			|| operand_start + 2 != self.chunk.code_len()
+			|| self.chunk.code()[operand_start] != OpCode::Constant as u8
+		{
+			return false;
+		}
+		let idx = self.chunk.code()[operand_start + 1] as usize;
+		match self.chunk.constant(idx) {
+			Some(Value::Number(n)) => {
+				self.chunk.set_constant(idx, Value::Number(-n));
+				true
+			}
+			_ => false,
+		}
+	}
+
 	fn binary(&mut self) -> Result<(), Error> {
 		let operator_kind = self.parser.previous.as_ref().unwrap().kind;
 		let rule = self.get_rule(&operator_kind);
-		self.parse_precedence(unsafe {
-			std::mem::transmute::<u32, Precedence>(rule.precedence as u32 + 1)
-		})?;
+		self.parse_precedence(rule.precedence.next())?;
 		match operator_kind {
 			TokenKind::Plus => self.emit_byte(OpCode::Add as u8),
 			TokenKind::Minus => self.emit_byte(OpCode::Subtract as u8),
 			TokenKind::Star => self.emit_byte(OpCode::Multiply as u8),
 			TokenKind::Slash => self.emit_byte(OpCode::Divide as u8),
+			TokenKind::Div => self.emit_byte(OpCode::FloorDivide as u8),
+			TokenKind::Percent => self.emit_byte(OpCode::Modulo as u8),
+			TokenKind::Mod => self.emit_byte(OpCode::FloorModulo as u8),
 			TokenKind::BangEqual => self.emit_bytes([OpCode::Equal as u8, OpCode::Not as u8]),
 			TokenKind::EqualEqual => self.emit_byte(OpCode::Equal as u8),
 			TokenKind::Greater => self.emit_byte(OpCode::Greater as u8),
@@ -367,35 +1375,176 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			TokenKind::LessEqual => self.emit_bytes([OpCode::Greater as u8, OpCode::Not as u8]),
 			_ => panic!("invalid operator: {:?}", operator_kind),
 		}
+		self.tree_node(operator_symbol(operator_kind), 2);
+		Ok(())
+	}
+
+	/// `value is Type`, whether `value` is of one of the built-in [Type]s. The type is a name
+	/// known at compile time rather than an expression, it's encoded in the instruction.
+	fn is_type(&mut self) -> Result<(), Error> {
+		let name = match self.parser.current.as_ref() {
+			Some(Token {
+				kind: TokenKind::Identifier(ident),
+				..
+			}) => *ident,
+			_ => return Err(Error::ExpectedTypeName),
+		};
+		self.advance()?;
+		let ty = Type::from_name(name).ok_or_else(|| Error::UnknownType {
+			name: name.to_string(),
+		})?;
+		self.emit_bytes([OpCode::IsType as u8, ty as u8]);
+		self.tree_leaf(ty);
+		self.tree_node("is", 2);
+		Ok(())
+	}
+
+	/// `**` binds tighter than unary operators, so `-2 ** 2` is `-(2 ** 2)`. It's right associative,
+	/// `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+	fn exponent(&mut self) -> Result<(), Error> {
+		self.parse_precedence(Precedence::Exponent)?;
+		self.emit_byte(OpCode::Power as u8);
+		self.tree_node("**", 2);
+		Ok(())
+	}
+
+	/// `condition ? then : else`. The condition is evaluated once and only one of the branches
+	/// runs. Right associative, `a ? b : c ? d : e` is `a ? b : (c ? d : e)`.
+	fn conditional(&mut self) -> Result<(), Error> {
+		let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+		self.emit_byte(OpCode::Pop as u8);
+		self.expression()?;
+		self.consume(
+			Some(TokenKind::Colon),
+			Error::ExpectedToken {
+				token: ":",
+				after: "then branch of conditional expression",
+			},
+		)?;
+		let end_jump = self.emit_jump(OpCode::Jump);
+
+		self.patch_jump(else_jump)?;
+		self.emit_byte(OpCode::Pop as u8);
+		self.parse_precedence(Precedence::Conditional)?;
+		self.tree_node("?:", 3);
+		self.patch_jump(end_jump)
+	}
+
+	fn coalesce(&mut self) -> Result<(), Error> {
+		let end_jump = self.emit_jump(OpCode::JumpIfNotNil);
+		self.emit_byte(OpCode::Pop as u8);
+		self.parse_precedence(Precedence::Coalesce)?;
+		self.tree_node("??", 2);
+		self.patch_jump(end_jump)
+	}
+
+	fn call(&mut self) -> Result<(), Error> {
+		let arg_count = self.argument_list()?;
+		self.emit_bytes([OpCode::Call as u8, arg_count]);
+		self.tree_node("call", 1 + arg_count as usize);
 		Ok(())
 	}
 
+	/// Comma separated arguments up to the closing `)`, which may be preceded by a trailing comma
+	fn argument_list(&mut self) -> Result<u8, Error> {
+		let mut arg_count: u8 = 0;
+		while !self.check(Some(TokenKind::RightParen)) {
+			self.expression()?;
+			arg_count = arg_count.checked_add(1).ok_or(Error::TooManyArguments)?;
+			if !self.matches(Some(TokenKind::Comma))? {
+				break;
+			}
+		}
+		self.consume(
+			Some(TokenKind::RightParen),
+			Error::ExpectedToken {
+				token: ")",
+				after: "arguments",
+			},
+		)?;
+		Ok(arg_count)
+	}
+
 	fn literal(&mut self) -> Result<(), Error> {
-		match self.parser.previous.as_ref().unwrap().kind {
-			TokenKind::Nil => self.emit_byte(OpCode::Nil as u8),
-			TokenKind::False => self.emit_byte(OpCode::False as u8),
-			TokenKind::True => self.emit_byte(OpCode::True as u8),
+		let (opcode, name) = match self.parser.previous.as_ref().unwrap().kind {
+			TokenKind::Nil => (OpCode::Nil, "nil"),
+			TokenKind::False => (OpCode::False, "false"),
+			TokenKind::True => (OpCode::True, "true"),
 			t => panic!("Invalid token: {:?}", t),
-		}
+		};
+		self.emit_byte(opcode as u8);
+		self.tree_leaf(name);
 		Ok(())
 	}
 
+	/// There are no classes yet, so `this` is always outside of a method. Without this rule it
+	/// would be reported as a missing expression.
+	fn this(&mut self) -> Result<(), Error> {
+		Err(Error::ThisOutsideClass)
+	}
+
 	fn variable(&mut self) -> Result<(), Error> {
 		let name = match self.parser.previous.as_ref().unwrap().kind {
 			TokenKind::Identifier(ident) => ident,
 			kind => panic!("Expected Identifier, got {kind:?}"),
 		};
-		let name = self.objects.copy_string(name);
 		self.named_variable(name)
 	}
 
-	fn named_variable(&mut self, name: *mut Object) -> Result<(), Error> {
-		let arg = self.identifier_constant(name)?;
-		self.emit_bytes([OpCode::GetGlobal as u8, arg]);
+	fn named_variable(&mut self, name: &'a str) -> Result<(), Error> {
+		let assign = self.can_assign && self.matches(Some(TokenKind::Equal))?;
+		self.tree_leaf(name);
+		if assign {
+			self.expression()?;
+			self.tree_node("=", 2);
+		}
+
+		if let Some(slot) = self.resolve_local(name)? {
+			let op = if assign {
+				OpCode::SetLocal
+			} else {
+				OpCode::GetLocal
+			};
+			self.emit_bytes([op as u8, slot]);
+			if assign {
+				self.assignment_end = Some(self.chunk.code_len());
+			}
+			return Ok(());
+		}
+
+		let name = self.objects.intern(name)?;
+		let [hi, lo] = self.global_slot(name)?.to_be_bytes();
+		let op = if assign {
+			OpCode::SetGlobal
+		} else {
+			OpCode::GetGlobal
+		};
+		self.emit_bytes([op as u8, hi, lo]);
+		if assign {
+			self.assignment_end = Some(self.chunk.code_len());
+		}
 		Ok(())
 	}
 
+	fn resolve_local(&self, name: &str) -> Result<Option<u8>, Error> {
+		let Some(slot) = self.locals.iter().rposition(|local| local.name == name) else {
+			return Ok(None);
+		};
+		if self.locals[slot].depth.is_none() {
+			return Err(Error::ReadLocalInOwnInitializer);
+		}
+		// add_local caps the number of locals at u8::MAX + 1
+		Ok(Some(slot as u8))
+	}
+
 	fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), Error> {
+		self.nested(1, |compiler| {
+			compiler.parse_precedence_unchecked(precedence)
+		})
+	}
+
+	/// [Compiler::parse_precedence] without the nesting limit
+	fn parse_precedence_unchecked(&mut self, precedence: Precedence) -> Result<(), Error> {
 		self.advance()?;
 
 		let Some(prefix_rule): Option<ParseFn> = self
@@ -408,12 +1557,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			return Err(Error::ExpectedExpression);
 		};
 
+		let can_assign = precedence as u32 <= Precedence::Assignment as u32;
+		self.can_assign = can_assign;
 		prefix_rule(self)?;
 
-		loop {
-			let Some(ref current_token) = self.parser.current else {
-				break;
-			};
+		while let Some(ref current_token) = self.parser.current {
 			if precedence as u32 > self.get_rule(&current_token.kind).precedence as u32 {
 				break;
 			}
@@ -426,39 +1574,102 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 			infix_rule(self)?;
 		}
 
+		if can_assign && self.matches(Some(TokenKind::Equal))? {
+			return Err(Error::InvalidAssignmentTarget);
+		}
+
 		Ok(())
 	}
 
-	fn parse_variable(&mut self, err_msg: Error) -> Result<u8, Error> {
-		let var_ident = match self.parser.current.as_ref() {
+	/// Runs `parse` `levels` nesting levels deeper, failing if that's past [Options::max_nesting]
+	fn nested(
+		&mut self,
+		levels: usize,
+		parse: impl FnOnce(&mut Self) -> Result<(), Error>,
+	) -> Result<(), Error> {
+		if self.nesting + levels > self.options.max_nesting {
+			return Err(Error::NestingTooDeep {
+				limit: self.options.max_nesting,
+			});
+		}
+		self.nesting += levels;
+		let result = parse(self);
+		self.nesting -= levels;
+		result
+	}
+
+	fn parse_variable(&mut self, err_msg: Error) -> Result<u16, Error> {
+		let name = match self.parser.current.as_ref() {
 			Some(Token {
 				kind: TokenKind::Identifier(ident),
 				..
-			}) => {
-				let ident = self.objects.copy_string(ident);
-				self.advance()?;
-				ident
-			}
+			}) => *ident,
 			_ => return Err(err_msg),
 		};
+		self.advance()?;
+
+		self.declare_variable(name)?;
+		if self.scope_depth > 0 {
+			return Ok(0);
+		}
+
+		let var_ident = self.objects.intern(name)?;
+		self.global_slot(var_ident)
+	}
 
-		self.identifier_constant(var_ident)
+	fn global_slot(&mut self, name: *mut Object) -> Result<u16, Error> {
+		let slot = self.globals.resolve(name.cast::<ObjString>());
+		u16::try_from(slot).map_err(|_| Error::TooManyGlobals)
+	}
+
+	fn declare_variable(&mut self, name: &'a str) -> Result<(), Error> {
+		if self.scope_depth == 0 {
+			return Ok(());
+		}
+		// Shadowing a variable of an enclosing scope is fine, redeclaring one in the same scope isn't
+		let duplicate = self
+			.locals
+			.iter()
+			.rev()
+			.take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+			.any(|local| local.name == name);
+		if duplicate {
+			return Err(Error::DuplicateDeclaration {
+				name: name.to_string(),
+			});
+		}
+		self.add_local(name)
+	}
+
+	fn add_local(&mut self, name: &'a str) -> Result<(), Error> {
+		if self.locals.len() > u8::MAX as usize {
+			return Err(Error::TooManyLocals);
+		}
+		self.locals.push(Local { name, depth: None });
+		Ok(())
 	}
 
-	fn identifier_constant(&mut self, var_ident: *mut Object) -> Result<u8, Error> {
-		self.make_constant(Value::Object(var_ident))
+	fn mark_initialized(&mut self) {
+		if let Some(local) = self.locals.last_mut() {
+			local.depth = Some(self.scope_depth);
+		}
 	}
 
-	fn define_variable(&mut self, global: u8) {
-		self.emit_bytes([OpCode::DefineGlobal as u8, global]);
+	fn define_variable(&mut self, global: u16) {
+		if self.scope_depth > 0 {
+			self.mark_initialized();
+			return;
+		}
+		let [hi, lo] = global.to_be_bytes();
+		self.emit_bytes([OpCode::DefineGlobal as u8, hi, lo]);
 	}
 
 	fn get_rule(&self, kind: &TokenKind<'a>) -> ParseRule<'a, 'b, 'c> {
 		match kind {
 			TokenKind::LeftParen => ParseRule {
 				prefix: Some(Compiler::grouping),
-				infix: None,
-				precedence: Precedence::None,
+				infix: Some(Compiler::call),
+				precedence: Precedence::Call,
 			},
 			TokenKind::RightParen => ParseRule {
 				prefix: None,
@@ -485,6 +1696,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Colon => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Dot => ParseRule {
 				prefix: None,
 				infix: None,
@@ -510,6 +1726,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: Some(Compiler::binary),
 				precedence: Precedence::Factor,
 			},
+			TokenKind::Percent => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::binary),
+				precedence: Precedence::Factor,
+			},
 			TokenKind::Bang => ParseRule {
 				prefix: Some(Compiler::unary),
 				infix: None,
@@ -530,6 +1751,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: Some(Compiler::binary),
 				precedence: Precedence::Equality,
 			},
+			TokenKind::EqualGreater => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Greater => ParseRule {
 				prefix: None,
 				infix: Some(Compiler::binary),
@@ -550,6 +1776,21 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: Some(Compiler::binary),
 				precedence: Precedence::Comparison,
 			},
+			TokenKind::StarStar => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::exponent),
+				precedence: Precedence::Exponent,
+			},
+			TokenKind::Question => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::conditional),
+				precedence: Precedence::Conditional,
+			},
+			TokenKind::QuestionQuestion => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::coalesce),
+				precedence: Precedence::Coalesce,
+			},
 			TokenKind::Identifier(_) => ParseRule {
 				prefix: Some(Compiler::variable),
 				infix: None,
@@ -570,11 +1811,36 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Break => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
+			TokenKind::Catch => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Class => ParseRule {
 				prefix: None,
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Continue => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
+			TokenKind::Div => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::binary),
+				precedence: Precedence::Factor,
+			},
+			TokenKind::Do => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Else => ParseRule {
 				prefix: None,
 				infix: None,
@@ -600,6 +1866,26 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Import => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
+			TokenKind::Is => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::is_type),
+				precedence: Precedence::Comparison,
+			},
+			TokenKind::Match => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
+			TokenKind::Mod => ParseRule {
+				prefix: None,
+				infix: Some(Compiler::binary),
+				precedence: Precedence::Factor,
+			},
 			TokenKind::Nil => ParseRule {
 				prefix: Some(Compiler::literal),
 				infix: None,
@@ -615,6 +1901,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Repeat => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Return => ParseRule {
 				prefix: None,
 				infix: None,
@@ -626,6 +1917,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				precedence: Precedence::None,
 			},
 			TokenKind::This => ParseRule {
+				prefix: Some(Self::this),
+				infix: None,
+				precedence: Precedence::None,
+			},
+			TokenKind::Throw => ParseRule {
 				prefix: None,
 				infix: None,
 				precedence: Precedence::None,
@@ -635,6 +1931,11 @@ impl<'a, 'b, 'c> Compiler<'a, 'b, 'c> {
 				infix: None,
 				precedence: Precedence::None,
 			},
+			TokenKind::Try => ParseRule {
+				prefix: None,
+				infix: None,
+				precedence: Precedence::None,
+			},
 			TokenKind::Var => ParseRule {
 				prefix: None,
 				infix: None,
@@ -653,3 +1954,54 @@ struct Parser<'a> {
 	current: Option<Token<'a>>,
 	previous: Option<Token<'a>>,
 }
+
+/// How `kind` is written in the parse tree
+fn operator_symbol(kind: TokenKind) -> &'static str {
+	match kind {
+		TokenKind::Plus => "+",
+		TokenKind::Minus => "-",
+		TokenKind::Star => "*",
+		TokenKind::Slash => "/",
+		TokenKind::Div => "div",
+		TokenKind::Percent => "%",
+		TokenKind::Mod => "mod",
+		TokenKind::Bang => "!",
+		TokenKind::BangEqual => "!=",
+		TokenKind::EqualEqual => "==",
+		TokenKind::Greater => ">",
+		TokenKind::GreaterEqual => ">=",
+		TokenKind::Less => "<",
+		TokenKind::LessEqual => "<=",
+		kind => panic!("not an operator: {kind:?}"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Precedence;
+
+	#[test]
+	fn precedence_next() {
+		let ascending = [
+			Precedence::None,
+			Precedence::Assignment,
+			Precedence::Conditional,
+			Precedence::Coalesce,
+			Precedence::Or,
+			Precedence::And,
+			Precedence::Equality,
+			Precedence::Comparison,
+			Precedence::Term,
+			Precedence::Factor,
+			Precedence::Unary,
+			Precedence::Exponent,
+			Precedence::Call,
+			Precedence::Primary,
+		];
+		for pair in ascending.windows(2) {
+			assert_eq!(pair[0].next(), pair[1]);
+			assert_eq!(pair[0].next() as u32, pair[0] as u32 + 1);
+		}
+		assert_eq!(Precedence::Primary.next(), Precedence::Primary);
+	}
+}
@@ -0,0 +1,144 @@
+use crate::object::{AllocationLimitExceeded, Allocator, ObjString};
+use crate::table::Table;
+use crate::value::Value;
+
+/// Global variables. The compiler resolves each name to a slot once, at run time globals are
+/// read and written by slot without hashing the name.
+#[derive(Default)]
+pub struct Globals {
+	/// Maps names to their slot in `values`, as a [Value::Number]
+	slots: Table,
+	/// Name of each slot, for error messages
+	names: Vec<*mut ObjString>,
+	/// `None` until the variable is defined
+	values: Vec<Option<Value>>,
+}
+
+impl Globals {
+	/// Returns the slot of `name`, reserving a new (undefined) one the first time a name is seen
+	pub fn resolve(&mut self, name: *mut ObjString) -> usize {
+		if let Some(Value::Number(slot)) = self.slots.get(name) {
+			return *slot as usize;
+		}
+		let slot = self.values.len();
+		self.slots.set(name, Value::Number(slot as f64));
+		self.names.push(name);
+		self.values.push(None);
+		slot
+	}
+
+	/// Makes room for `additional` more names without reallocating
+	pub fn reserve(&mut self, additional: usize) {
+		self.slots.reserve(additional);
+		self.names.reserve(additional);
+		self.values.reserve(additional);
+	}
+
+	/// Value of the variable in `slot`, `None` if it hasn't been defined
+	pub fn get(&self, slot: usize) -> Option<Value> {
+		self.values.get(slot).copied().flatten()
+	}
+
+	/// Defines or assigns the variable in `slot`. Returns `false` if there's no such slot.
+	pub fn set(&mut self, slot: usize, value: Value) -> bool {
+		match self.values.get_mut(slot) {
+			Some(variable) => {
+				*variable = Some(value);
+				true
+			}
+			None => false,
+		}
+	}
+
+	pub fn define(&mut self, name: *mut ObjString, value: Value) {
+		let slot = self.resolve(name);
+		self.set(slot, value);
+	}
+
+	/// Name of the variable in `slot`, `None` if there's no such slot
+	pub fn name(&self, slot: usize) -> Option<*mut ObjString> {
+		self.names.get(slot).copied()
+	}
+
+	/// Defined variables with their values, in the order their names were first seen
+	pub fn iter(&self) -> impl Iterator<Item = (&ObjString, Value)> {
+		self.names
+			.iter()
+			.zip(&self.values)
+			.filter_map(|(name, value)| Some((unsafe { &**name }, (*value)?)))
+	}
+
+	/// Number of defined variables
+	pub fn len(&self) -> usize {
+		self.values.iter().filter(|value| value.is_some()).count()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Copies the defined variables out of the VM, see [Snapshot]
+	pub fn snapshot(&self) -> Snapshot {
+		let globals = self
+			.iter()
+			.filter_map(|(name, value)| {
+				let value = match value {
+					Value::Nil => SnapshotValue::Nil,
+					Value::Bool(b) => SnapshotValue::Bool(b),
+					Value::Number(n) => SnapshotValue::Number(n),
+					// Globals keep their objects alive
+					Value::Object(_) => {
+						SnapshotValue::String(unsafe { value.as_str() }?.to_string())
+					}
+				};
+				Some((name.as_str().to_string(), value))
+			})
+			.collect();
+		Snapshot { globals }
+	}
+
+	/// Defines every variable in `snapshot`, interning names and strings through `objects`.
+	/// Variables that aren't in the snapshot are left as they are.
+	pub fn restore(
+		&mut self,
+		snapshot: &Snapshot,
+		objects: &mut Allocator,
+	) -> Result<(), AllocationLimitExceeded> {
+		for (name, value) in &snapshot.globals {
+			let value = match value {
+				SnapshotValue::Nil => Value::Nil,
+				SnapshotValue::Bool(b) => Value::Bool(*b),
+				SnapshotValue::Number(n) => Value::Number(*n),
+				SnapshotValue::String(s) => Value::Object(objects.intern(s)?),
+			};
+			let name = objects.intern(name)?;
+			self.define(name.cast::<ObjString>(), value);
+		}
+		Ok(())
+	}
+
+	pub fn allocated_bytes(&self) -> usize {
+		self.slots.allocated_bytes()
+			+ self.names.capacity() * std::mem::size_of::<*mut ObjString>()
+			+ self.values.capacity() * std::mem::size_of::<Option<Value>>()
+	}
+}
+
+/// Global variables copied out of a VM, with no pointers into its heap, so they can be kept
+/// around after the VM is reset or dropped and restored into another one.
+///
+/// Only nil, bools, numbers and strings are saved. Natives are skipped, they're host code the
+/// embedder defines again the same way it did the first time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+	/// Names and values, in the order the names were first seen
+	pub globals: Vec<(String, SnapshotValue)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotValue {
+	Nil,
+	Bool(bool),
+	Number(f64),
+	String(String),
+}
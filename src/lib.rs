@@ -1,5 +1,7 @@
 pub mod chunk;
 pub mod compiler;
+pub mod globals;
+pub mod native;
 pub mod object;
 pub mod scanner;
 pub mod table;
@@ -1,19 +1,17 @@
+use lox_v2::compiler;
 use lox_v2::vm::Vm;
-use std::io::stdin;
+use std::io::{stdin, Stdout, Write};
 
 fn main() {
 	let mut args: Vec<String> = std::env::args().skip(1).collect();
-	let debug = if let Some(debug_param_idx) = args.iter().position(|arg| arg == "--debug") {
-		args.remove(debug_param_idx);
-		true
-	} else {
-		false
-	};
+	let debug = take_flag(&mut args, "--debug");
+	let parse_tree = take_flag(&mut args, "--parse-tree");
 	let result = match args.as_slice() {
+		[filename] if parse_tree => print_parse_tree(filename),
 		[] => repl(debug),
 		[filename] => run_file(filename, debug),
 		_ => {
-			eprintln!("Usage:\n\tlox-v2 [path]\n");
+			eprintln!("Usage:\n\tlox-v2 [--debug] [--parse-tree] [path | -]\n");
 			std::process::exit(64);
 		}
 	};
@@ -24,24 +22,86 @@ fn main() {
 	}
 }
 
-fn repl(debug: bool) -> Result<(), Box<dyn std::error::Error>> {
-	let mut vm = Vm::default();
+/// Removes `flag` from `args`, returns whether it was there
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+	match args.iter().position(|arg| arg == flag) {
+		Some(idx) => {
+			args.remove(idx);
+			true
+		}
+		None => false,
+	}
+}
+
+fn new_vm(debug: bool) -> Vm<Stdout> {
+	let mut vm = Vm::default()
+		.with_prelude()
+		.with_std_natives()
+		.with_io_natives();
 	vm.debug = debug;
+	vm
+}
+
+fn repl(debug: bool) -> Result<(), Box<dyn std::error::Error>> {
+	let mut vm = new_vm(debug);
 
 	for line in stdin().lines() {
 		let line = line?;
-		vm.interpret(&line)?;
+		if is_blank(&line) {
+			continue;
+		}
+		match line.strip_prefix(':') {
+			Some("reset") => {
+				vm.reset_all();
+				vm = vm.with_prelude().with_std_natives().with_io_natives();
+			}
+			Some(command) => repl_command(&vm, command.trim()),
+			None => {
+				vm.interpret(&line)?;
+			}
+		}
 	}
 
 	Ok(())
 }
 
+/// Lines with nothing but whitespace or a comment, there's no point in compiling them
+fn is_blank(line: &str) -> bool {
+	let line = line.trim();
+	line.is_empty() || line.starts_with("//")
+}
+
+/// Handles `:command` lines, which inspect the VM instead of being interpreted
+fn repl_command<W: Write>(vm: &Vm<W>, command: &str) {
+	match command {
+		"dump" => match vm.last_chunk() {
+			Some(chunk) => print!("{}", chunk.disassemble("repl")),
+			None => println!("Nothing compiled yet"),
+		},
+		"globals" => {
+			for (name, value) in vm.globals() {
+				println!("{name} = {value}");
+			}
+		}
+		_ => eprintln!("Unknown command ':{command}', expected ':dump', ':globals' or ':reset'"),
+	}
+}
+
 fn run_file(filename: &str, debug: bool) -> Result<(), Box<dyn std::error::Error>> {
-	let source = std::fs::read_to_string(filename)?;
+	let mut vm = new_vm(debug);
+	if filename == "-" {
+		// The whole program, as opposed to the REPL which runs it line by line
+		let source = std::io::read_to_string(stdin())?;
+		vm.interpret(&source)?;
+	} else {
+		vm.interpret_file(filename)?;
+	}
 
-	let mut vm = Vm::default();
-	vm.debug = debug;
-	vm.interpret(&source)?;
+	Ok(())
+}
 
+fn print_parse_tree(filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+	let source = std::fs::read_to_string(filename)?;
+	println!("{}", compiler::parse_tree(&source)?);
 	Ok(())
 }
@@ -0,0 +1,292 @@
+use crate::object::Allocator;
+use crate::value::Value;
+use crate::vm::{InvalidTypeError, InvalidTypeErrorKind, RuntimeError};
+
+/// Signature of functions implemented in Rust. `args` has exactly as many values as the native's
+/// arity, unless it's variadic.
+pub type NativeFn = fn(&mut Context, &[Value]) -> Result<Value, RuntimeError>;
+
+/// Parts of the VM natives have access to
+pub struct Context<'a> {
+	pub objects: &'a mut Allocator,
+	pub rng: &'a mut Rng,
+}
+
+/// xorshift64* generator behind `random` and `randomInt`. Not suitable for anything security
+/// related.
+#[derive(Debug, Clone)]
+pub struct Rng {
+	state: u64,
+}
+
+impl Rng {
+	pub fn new(seed: u64) -> Self {
+		// Run the seed through splitmix64, xorshift gets stuck on a zero state and does poorly with
+		// states that have few bits set
+		let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^= z >> 31;
+		Rng {
+			state: if z == 0 { 0x9e3779b97f4a7c15 } else { z },
+		}
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		self.state.wrapping_mul(0x2545f4914f6cdd1d)
+	}
+
+	/// Uniformly distributed in `[0, 1)`
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+}
+
+/// `readFile(path)` returns the contents of the file at `path` as a string
+pub fn read_file(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let path = string_arg(&args[0])?;
+	let contents = std::fs::read_to_string(path)
+		.map_err(|err| RuntimeError::Native(format!("Can't read '{path}': {err}")))?;
+	Ok(Value::Object(ctx.objects.take_string(contents)?))
+}
+
+/// `writeFile(path, contents)` writes `contents` (strings as is, other values as they'd be
+/// printed) to the file at `path`, replacing it if it already exists
+pub fn write_file(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let path = string_arg(&args[0])?;
+	std::fs::write(path, args[1].to_string())
+		.map_err(|err| RuntimeError::Native(format!("Can't write '{path}': {err}")))?;
+	Ok(Value::Nil)
+}
+
+/// `getenv(name)` returns the value of the environment variable `name`, or `nil` if it's not set
+pub fn getenv(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let name = string_arg(&args[0])?;
+	match std::env::var(name) {
+		Ok(value) => Ok(Value::Object(ctx.objects.take_string(value)?)),
+		Err(std::env::VarError::NotPresent) => Ok(Value::Nil),
+		Err(err @ std::env::VarError::NotUnicode(_)) => {
+			Err(RuntimeError::Native(format!("Can't read '{name}': {err}")))
+		}
+	}
+}
+
+/// `random()` returns a number in `[0, 1)`
+pub fn random(ctx: &mut Context, _: &[Value]) -> Result<Value, RuntimeError> {
+	Ok(Value::Number(ctx.rng.next_f64()))
+}
+
+/// `randomInt(lo, hi)` returns a whole number in `[lo, hi]`, both bounds included
+pub fn random_int(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let lo = integer_arg(&args[0])?;
+	let hi = integer_arg(&args[1])?;
+	if lo > hi {
+		return Err(RuntimeError::Native(format!(
+			"randomInt: {lo} is greater than {hi}"
+		)));
+	}
+	let range = (hi - lo) as u64 + 1;
+	Ok(Value::Number(
+		(lo + (ctx.rng.next_u64() % range) as i64) as f64,
+	))
+}
+
+/// `abs(x)` returns the absolute value of `x`
+pub fn abs(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	Ok(Value::Number(number_arg(&args[0])?.abs()))
+}
+
+/// `min(x, ...)` returns the smallest of its arguments
+pub fn min(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	extreme("min", args, f64::min)
+}
+
+/// `max(x, ...)` returns the largest of its arguments
+pub fn max(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	extreme("max", args, f64::max)
+}
+
+/// `format(fmt, ...)` replaces each `{}` in `fmt` with the next argument, as it'd be printed.
+/// `{{` and `}}` stand for literal braces.
+pub fn format(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let Some((fmt, args)) = args.split_first() else {
+		return Err(RuntimeError::Native(
+			"format expects a format string".to_string(),
+		));
+	};
+	let fmt = string_arg(fmt)?;
+	let mut out = String::with_capacity(fmt.len());
+	let mut args = args.iter();
+	let mut chars = fmt.chars().peekable();
+	while let Some(c) = chars.next() {
+		match (c, chars.peek()) {
+			('{', Some('}')) => {
+				chars.next();
+				let arg = args.next().ok_or_else(|| {
+					RuntimeError::Native(format!("format: too few arguments for '{fmt}'"))
+				})?;
+				out.push_str(&arg.to_string());
+			}
+			('{', Some('{')) | ('}', Some('}')) => {
+				chars.next();
+				out.push(c);
+			}
+			('{' | '}', _) => {
+				return Err(RuntimeError::Native(format!(
+					"format: unmatched '{c}' in '{fmt}'"
+				)));
+			}
+			_ => out.push(c),
+		}
+	}
+	if args.next().is_some() {
+		return Err(RuntimeError::Native(format!(
+			"format: too many arguments for '{fmt}'"
+		)));
+	}
+	Ok(Value::Object(ctx.objects.take_string(out)?))
+}
+
+/// `ord(s)` returns the code point of the first character of `s`
+pub fn ord(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let c = string_arg(&args[0])?
+		.chars()
+		.next()
+		.ok_or_else(|| RuntimeError::Native("ord: empty string".to_string()))?;
+	Ok(Value::Number(c as u32 as f64))
+}
+
+/// `chr(n)` returns a string made of the single character with code point `n`
+pub fn chr(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	let n = integer_arg(&args[0])?;
+	let c = u32::try_from(n)
+		.ok()
+		.and_then(char::from_u32)
+		.ok_or_else(|| RuntimeError::Native(format!("chr: {n} is not a valid code point")))?;
+	Ok(Value::Object(ctx.objects.take_string(c.to_string())?))
+}
+
+/// `hex(n)` returns the non-negative integer `n` in base 16, in lowercase and without a `0x`
+/// prefix: `hex(255)` is `"ff"`. Fractional numbers are a type error, negative ones an error.
+pub fn hex(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	in_base(ctx, "hex", &args[0], |n| format!("{n:x}"))
+}
+
+/// `oct(n)` returns `n` in base 8, following the rules of `hex`
+pub fn oct(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	in_base(ctx, "oct", &args[0], |n| format!("{n:o}"))
+}
+
+/// `bin(n)` returns `n` in base 2, following the rules of `hex`
+pub fn bin(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	in_base(ctx, "bin", &args[0], |n| format!("{n:b}"))
+}
+
+fn in_base(
+	ctx: &mut Context,
+	name: &str,
+	value: &Value,
+	format: fn(u64) -> String,
+) -> Result<Value, RuntimeError> {
+	let n = integer_arg(value)?;
+	let n =
+		u64::try_from(n).map_err(|_| RuntimeError::Native(format!("{name}: {n} is negative")))?;
+	Ok(Value::Object(ctx.objects.take_string(format(n))?))
+}
+
+/// `toFixed(n, digits)` returns `n` rounded to `digits` (0 to 100) digits after the decimal
+/// point: `toFixed(3.14159, 2)` is `"3.14"`. `nan` and infinities are returned as they'd print.
+pub fn to_fixed(ctx: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	const MAX_DIGITS: i64 = 100;
+
+	let n = number_arg(&args[0])?;
+	let digits = integer_arg(&args[1])?;
+	if !(0..=MAX_DIGITS).contains(&digits) {
+		return Err(RuntimeError::Native(format!(
+			"toFixed: {digits} digits is outside of 0 to {MAX_DIGITS}"
+		)));
+	}
+	let fixed = if n.is_finite() {
+		format!("{n:.*}", digits as usize)
+	} else {
+		args[0].to_string()
+	};
+	Ok(Value::Object(ctx.objects.take_string(fixed)?))
+}
+
+/// `assertEq(a, b)` fails with [RuntimeError::AssertionFailed] unless `a == b`, for tests written
+/// in Lox
+pub fn assert_eq(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	assertion(args[0] == args[1], "==", args)
+}
+
+/// `assertNeq(a, b)` fails with [RuntimeError::AssertionFailed] unless `a != b`
+pub fn assert_neq(_: &mut Context, args: &[Value]) -> Result<Value, RuntimeError> {
+	assertion(args[0] != args[1], "!=", args)
+}
+
+fn assertion(holds: bool, op: &'static str, args: &[Value]) -> Result<Value, RuntimeError> {
+	if holds {
+		return Ok(Value::Nil);
+	}
+	// Quoted, so that `"1"` and `1` can be told apart
+	// Arguments are on the VM's stack for the duration of the call
+	let render = |value: &Value| match unsafe { value.as_str() } {
+		Some(str) => format!("{str:?}"),
+		None => value.to_string(),
+	};
+	Err(RuntimeError::AssertionFailed {
+		left: render(&args[0]),
+		op,
+		right: render(&args[1]),
+	})
+}
+
+fn extreme(name: &str, args: &[Value], pick: fn(f64, f64) -> f64) -> Result<Value, RuntimeError> {
+	let Some((first, rest)) = args.split_first() else {
+		return Err(RuntimeError::Native(format!(
+			"{name} expects at least one argument"
+		)));
+	};
+	let mut extreme = number_arg(first)?;
+	for arg in rest {
+		extreme = pick(extreme, number_arg(arg)?);
+	}
+	Ok(Value::Number(extreme))
+}
+
+fn number_arg(value: &Value) -> Result<f64, RuntimeError> {
+	match value {
+		Value::Number(n) => Ok(*n),
+		_ => Err(RuntimeError::InvalidType(InvalidTypeError {
+			value: *value,
+			kind: InvalidTypeErrorKind::ExpectedNumberArgument,
+		})),
+	}
+}
+
+fn string_arg(value: &Value) -> Result<&str, RuntimeError> {
+	// Arguments are on the VM's stack for the duration of the call
+	unsafe { value.as_obj_string() }
+		.map(|str| str.as_str())
+		.ok_or(RuntimeError::InvalidType(InvalidTypeError {
+			value: *value,
+			kind: InvalidTypeErrorKind::ExpectedStringArgument,
+		}))
+}
+
+/// Numbers without a fractional part that fit in 2^53, so they can be converted back to a
+/// [Value::Number] without loss
+fn integer_arg(value: &Value) -> Result<i64, RuntimeError> {
+	const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+	match value {
+		Value::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => Ok(*n as i64),
+		_ => Err(RuntimeError::InvalidType(InvalidTypeError {
+			value: *value,
+			kind: InvalidTypeErrorKind::ExpectedIntegerArgument,
+		})),
+	}
+}
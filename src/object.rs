@@ -1,15 +1,37 @@
 #![allow(clippy::result_unit_err, clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::native::NativeFn;
 use crate::table::{hash, Table};
 use crate::value::Value;
+use std::any::Any;
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, Ordering};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Allocation limit of {limit} objects exceeded")]
+pub struct AllocationLimitExceeded {
+	pub limit: usize,
+}
+
+/// How often looking a string up in the interned strings found it, see [Allocator::intern_stats]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct InternStats {
+	pub hits: usize,
+	pub misses: usize,
+}
 
 pub struct Allocator {
 	objects: AtomicPtr<Object>,
 	strings: Table,
+	interning: bool,
+	intern_stats: InternStats,
+
+	bytes_allocated: usize,
+	object_count: usize,
+	max_objects: Option<usize>,
 }
 
 impl Default for Allocator {
@@ -17,6 +39,11 @@ impl Default for Allocator {
 		Allocator {
 			objects: AtomicPtr::new(ptr::null_mut()),
 			strings: Table::default(),
+			interning: true,
+			intern_stats: InternStats::default(),
+			bytes_allocated: 0,
+			object_count: 0,
+			max_objects: None,
 		}
 	}
 }
@@ -28,31 +55,94 @@ impl Drop for Allocator {
 }
 
 impl Allocator {
-	fn put_obj<T: IsObject>(&mut self, obj: T) -> *mut Object {
+	fn put_obj<T: IsObject>(&mut self, obj: T) -> Result<*mut Object, AllocationLimitExceeded> {
+		if let Some(limit) = self.max_objects {
+			if self.object_count >= limit {
+				return Err(AllocationLimitExceeded { limit });
+			}
+		}
+
 		let obj = T::into_object(Box::into_raw(Box::new(obj)));
 		unsafe {
 			(*obj).next = self.objects.load(Ordering::Acquire);
+			self.bytes_allocated += (*obj).size();
 		}
+		self.object_count += 1;
 		self.objects.store(obj, Ordering::Release);
-		obj
+		Ok(obj)
 	}
 
-	/// Use only when you're sure that the `str` is unique (hasn't been allocated already).
-	fn new_string_object(&mut self, str: String) -> *mut Object {
+	/// Use only when you're sure that the `str` is unique (hasn't been allocated already), or when
+	/// it isn't going to be interned.
+	fn new_string_object(
+		&mut self,
+		str: String,
+		intern: bool,
+	) -> Result<*mut Object, AllocationLimitExceeded> {
 		let hash = hash(&str);
 		let obj = ObjString {
 			obj: Object {
 				kind: ObjectKind::String,
 				next: ptr::null_mut(),
 			},
+			char_len: str.chars().count(),
 			str,
 			hash,
 		};
-		let obj = self.put_obj(obj);
-		self.strings.set(obj.cast::<ObjString>(), Value::Nil);
-		obj
+		let obj = self.put_obj(obj)?;
+		if intern {
+			self.strings.set(obj.cast::<ObjString>(), Value::Nil);
+		}
+		Ok(obj)
 	}
 
+	/// Looks `str` up in the interned strings, counting the hit or miss
+	fn find_interned(&mut self, str: &str) -> Option<*mut Object> {
+		let interned = self.strings.find_string(str, hash(str));
+		match interned {
+			Some(_) => self.intern_stats.hits += 1,
+			None => self.intern_stats.misses += 1,
+		}
+		interned.map(ObjString::into_object)
+	}
+
+	pub fn new_native(
+		&mut self,
+		name: &str,
+		arity: Option<usize>,
+		function: NativeFn,
+	) -> Result<*mut Object, AllocationLimitExceeded> {
+		let obj = ObjNative {
+			obj: Object {
+				kind: ObjectKind::Native,
+				next: ptr::null_mut(),
+			},
+			name: name.to_string(),
+			arity,
+			function,
+		};
+		self.put_obj(obj)
+	}
+
+	/// Wraps a host value, see [ObjForeign]
+	pub fn new_foreign<T: Any>(
+		&mut self,
+		type_name: &str,
+		value: T,
+	) -> Result<*mut Object, AllocationLimitExceeded> {
+		let obj = ObjForeign {
+			obj: Object {
+				kind: ObjectKind::Foreign,
+				next: ptr::null_mut(),
+			},
+			type_name: type_name.to_string(),
+			value: Box::new(value),
+		};
+		self.put_obj(obj)
+	}
+
+	/// Frees every object. Pointers to them must not be used afterwards, but the allocator itself
+	/// can keep allocating.
 	pub fn free(&mut self) {
 		// Free objects
 		unsafe {
@@ -61,46 +151,108 @@ impl Allocator {
 			let mut object = self.objects.load(Ordering::Relaxed);
 			while !object.is_null() {
 				let next = (*object).next;
-				drop(Box::from_raw(object));
+				self.bytes_allocated -= (*object).size();
+				self.object_count -= 1;
+				drop_object(object);
 				object = next;
 			}
 		}
+		self.objects.store(ptr::null_mut(), Ordering::Relaxed);
+		self.strings = Table::default();
+	}
+
+	/// Whether `obj` is one of the live objects made by this allocator. Walks every object, for
+	/// checking pointers that may have been freed before dereferencing them.
+	pub fn owns(&self, obj: *const Object) -> bool {
+		let mut object = self.objects.load(Ordering::Acquire);
+		while !object.is_null() {
+			if ptr::eq(object, obj) {
+				return true;
+			}
+			// Objects in the list are alive, freeing them empties it
+			object = unsafe { (*object).next };
+		}
+		false
 	}
 
-	pub fn copy_object(&mut self, obj: *mut Object) -> *mut Object {
+	/// Number of bytes held by live objects, including the contents of strings
+	pub fn bytes_allocated(&self) -> usize {
+		self.bytes_allocated
+	}
+
+	pub fn object_count(&self) -> usize {
+		self.object_count
+	}
+
+	/// Hits and misses of interned string lookups so far
+	pub fn intern_stats(&self) -> InternStats {
+		self.intern_stats
+	}
+
+	/// Turns interning of new strings on or off. With it off, [Allocator::copy_string] and
+	/// [Allocator::take_string] skip the lookup and always allocate, which is cheaper when most
+	/// strings are unique. Strings still compare equal by contents, and names made with
+	/// [Allocator::intern] are interned either way.
+	pub fn set_interning(&mut self, interning: bool) {
+		self.interning = interning;
+	}
+
+	/// Limits the number of live objects. Allocating past the limit fails with
+	/// [AllocationLimitExceeded]. `None` removes the limit.
+	pub fn set_max_objects(&mut self, max_objects: Option<usize>) {
+		self.max_objects = max_objects;
+	}
+
+	pub fn copy_object(
+		&mut self,
+		obj: *mut Object,
+	) -> Result<*mut Object, AllocationLimitExceeded> {
 		let obj_ref = unsafe { &*obj };
 		match &obj_ref.kind {
 			ObjectKind::String => {
 				let str: &ObjString = unsafe { obj_ref.as_obj_string_unchecked() };
-				let hash = hash(str);
-				if let Some(interned) = self.strings.find_string(str, hash) {
-					return ObjString::into_object(interned);
-				}
-				self.new_string_object(str.str.clone())
+				self.copy_string(str)
 			}
+			// Natives are immutable and foreign values can't be cloned, both are shared instead
+			ObjectKind::Native | ObjectKind::Foreign => Ok(obj),
 		}
 	}
 
-	pub fn copy_string(&mut self, str: &str) -> *mut Object {
-		let hash = hash(str);
-		if let Some(interned) = self.strings.find_string(str, hash) {
-			return ObjString::into_object(interned);
+	pub fn copy_string(&mut self, str: &str) -> Result<*mut Object, AllocationLimitExceeded> {
+		if !self.interning {
+			return self.new_string_object(str.to_string(), false);
 		}
-		self.new_string_object(str.to_string())
+		self.intern(str)
 	}
 
-	pub fn take_string(&mut self, str: String) -> *mut Object {
-		let hash = hash(&str);
-		let interned = self.strings.find_string(&str, hash);
-		match interned {
-			Some(interned) => interned.cast::<Object>(),
-			None => self.new_string_object(str),
+	pub fn take_string(&mut self, str: String) -> Result<*mut Object, AllocationLimitExceeded> {
+		if !self.interning {
+			return self.new_string_object(str, false);
+		}
+		match self.find_interned(&str) {
+			Some(interned) => Ok(interned),
+			None => self.new_string_object(str, true),
+		}
+	}
+
+	/// Like [Allocator::copy_string], but interns even with interning turned off. For names,
+	/// which globals are looked up by identity.
+	pub fn intern(&mut self, str: &str) -> Result<*mut Object, AllocationLimitExceeded> {
+		match self.find_interned(str) {
+			Some(interned) => Ok(interned),
+			None => self.new_string_object(str.to_string(), true),
 		}
 	}
 }
 
-// Marker trait saying that the a given T has repr(C) and [Object] as a first field
-trait IsObject {
+/// An object type that can be downcast to from [Object], see [Object::downcast]
+///
+/// # Safety
+/// `Self` has to be `repr(C)` with [Object] as its first field, and objects of kind `KIND` must
+/// always be a `Self`
+pub unsafe trait IsObject {
+	const KIND: ObjectKind;
+
 	fn into_object(this: *mut Self) -> *mut Object;
 }
 
@@ -112,19 +264,50 @@ pub struct Object {
 }
 
 #[repr(u32)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ObjectKind {
 	String,
+	Native,
+	Foreign,
 }
 
 #[repr(C)]
 pub struct ObjString {
 	obj: Object,
 	str: String,
+	/// Counted once up front, strings are immutable
+	char_len: usize,
 	pub hash: u32,
 }
 
-impl IsObject for ObjString {
+unsafe impl IsObject for ObjString {
+	const KIND: ObjectKind = ObjectKind::String;
+
+	fn into_object(this: *mut Self) -> *mut Object {
+		unsafe {
+			// Asserts that [Object] is the first field in the struct
+			debug_assert!(ptr::eq(
+				(&mut (*this).obj) as *mut Object,
+				this.cast::<Object>()
+			));
+			(&mut (*this).obj) as *mut Object
+		}
+	}
+}
+
+/// A function implemented in Rust
+#[repr(C)]
+pub struct ObjNative {
+	obj: Object,
+	pub name: String,
+	/// Number of arguments the function takes, `None` if it takes any number of them
+	pub arity: Option<usize>,
+	pub function: NativeFn,
+}
+
+unsafe impl IsObject for ObjNative {
+	const KIND: ObjectKind = ObjectKind::Native;
+
 	fn into_object(this: *mut Self) -> *mut Object {
 		unsafe {
 			// Asserts that [Object] is the first field in the struct
@@ -137,10 +320,77 @@ impl IsObject for ObjString {
 	}
 }
 
+/// A value owned by the host, e.g. a file handle, that scripts can pass around but not look into.
+/// It's dropped along with the object.
+#[repr(C)]
+pub struct ObjForeign {
+	obj: Object,
+	/// Name of the value's type for scripts, it's printed as `<foreign type_name>`
+	pub type_name: String,
+	value: Box<dyn Any>,
+}
+
+unsafe impl IsObject for ObjForeign {
+	const KIND: ObjectKind = ObjectKind::Foreign;
+
+	fn into_object(this: *mut Self) -> *mut Object {
+		unsafe {
+			// Asserts that [Object] is the first field in the struct
+			debug_assert!(ptr::eq(
+				(&mut (*this).obj) as *mut Object,
+				this.cast::<Object>()
+			));
+			(&mut (*this).obj) as *mut Object
+		}
+	}
+}
+
+impl ObjForeign {
+	/// The wrapped value, `None` if it's not a `T`
+	pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+		self.value.downcast_ref()
+	}
+}
+
+impl Display for ObjForeign {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "<foreign {}>", self.type_name)
+	}
+}
+
+/// Drops `object` as the struct it was allocated as, so that what it owns is dropped too
+///
+/// # Safety
+/// `object` has to come from [Allocator::put_obj] and mustn't be used afterwards
+unsafe fn drop_object(object: *mut Object) {
+	match (*object).kind {
+		ObjectKind::String => drop(Box::from_raw(object.cast::<ObjString>())),
+		ObjectKind::Native => drop(Box::from_raw(object.cast::<ObjNative>())),
+		ObjectKind::Foreign => drop(Box::from_raw(object.cast::<ObjForeign>())),
+	}
+}
+
+impl Display for ObjNative {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "<native fn {}>", self.name)
+	}
+}
+
 impl ObjString {
 	pub fn as_str(&self) -> &str {
 		self
 	}
+
+	/// Length in bytes of the UTF-8 encoding. This is what `len()` through [Deref] to `str`
+	/// returns too.
+	pub fn byte_len(&self) -> usize {
+		self.str.len()
+	}
+
+	/// Length in characters (Unicode scalar values), the length a script sees
+	pub fn char_len(&self) -> usize {
+		self.char_len
+	}
 }
 
 impl Display for ObjString {
@@ -170,15 +420,50 @@ impl Deref for ObjString {
 }
 
 impl Object {
-	pub fn as_obj_string(&self) -> Result<&ObjString, ()> {
+	/// Size of the whole object, including heap memory it owns
+	pub fn size(&self) -> usize {
 		match self.kind {
 			ObjectKind::String => {
-				let obj_str: &ObjString = unsafe { &*(self as *const Self).cast::<ObjString>() };
-				Ok(obj_str)
+				let str = unsafe { self.as_string_unchecked() };
+				std::mem::size_of::<ObjString>() + str.capacity()
+			}
+			ObjectKind::Native => {
+				let native = unsafe { self.as_obj_native_unchecked() };
+				std::mem::size_of::<ObjNative>() + native.name.capacity()
+			}
+			ObjectKind::Foreign => {
+				let foreign: &ObjForeign = self.downcast().expect("kind is Foreign");
+				std::mem::size_of::<ObjForeign>()
+					+ foreign.type_name.capacity()
+					+ std::mem::size_of_val(&*foreign.value)
 			}
 		}
 	}
 
+	/// The object as a `T`, `None` if it's of a different kind
+	pub fn downcast<T: IsObject>(&self) -> Option<&T> {
+		(self.kind == T::KIND).then(|| unsafe { &*(self as *const Self).cast::<T>() })
+	}
+
+	/// Like [Object::downcast], but mutable
+	pub fn downcast_mut<T: IsObject>(&mut self) -> Option<&mut T> {
+		(self.kind == T::KIND).then(|| unsafe { &mut *(self as *mut Self).cast::<T>() })
+	}
+
+	pub fn as_obj_string(&self) -> Result<&ObjString, ()> {
+		self.downcast().ok_or(())
+	}
+
+	pub fn as_obj_native(&self) -> Result<&ObjNative, ()> {
+		self.downcast().ok_or(())
+	}
+
+	/// # Safety
+	/// `self` has to be an [ObjNative], i.e. its `kind` must be [ObjectKind::Native]
+	pub unsafe fn as_obj_native_unchecked(&self) -> &ObjNative {
+		&*(self as *const Self).cast::<ObjNative>()
+	}
+
 	/// # Safety
 	/// TODO(aqatl): Add safety doc
 	pub unsafe fn as_obj_string_unchecked(&self) -> &ObjString {
@@ -212,15 +497,22 @@ impl Object {
 					let obj_str = this.cast::<ObjString>();
 					Ok(obj_str)
 				}
+				_ => Err(()),
 			}
 		}
 	}
 }
 
+/// Each kind is matched explicitly, without a catch-all arm, so adding a kind doesn't compile until
+/// it's given a rendering
 impl Display for Object {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 		match &self.kind {
 			ObjectKind::String => Display::fmt(unsafe { self.as_string_unchecked() }, f),
+			ObjectKind::Native => Display::fmt(unsafe { self.as_obj_native_unchecked() }, f),
+			ObjectKind::Foreign => {
+				Display::fmt(self.downcast::<ObjForeign>().expect("kind is Foreign"), f)
+			}
 		}
 	}
 }
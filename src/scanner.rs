@@ -14,16 +14,22 @@ pub enum TokenKind<'a> {
 	Plus,
 	Slash,
 	Star,
+	Percent,
+	Colon,
 
 	// One or two character
 	Bang,
 	BangEqual,
 	Equal,
 	EqualEqual,
+	EqualGreater,
 	Greater,
 	GreaterEqual,
 	Less,
 	LessEqual,
+	StarStar,
+	Question,
+	QuestionQuestion,
 
 	// Literals
 	Identifier(&'a str),
@@ -32,23 +38,67 @@ pub enum TokenKind<'a> {
 
 	// Keywords
 	And,
+	Break,
+	Catch,
 	Class,
+	Continue,
+	Div,
+	Do,
 	Else,
 	False,
 	For,
 	Fun,
 	If,
+	Import,
+	Is,
+	Match,
+	Mod,
 	Nil,
 	Or,
 	Print,
+	Repeat,
 	Return,
 	Super,
 	This,
+	Throw,
 	True,
+	Try,
 	Var,
 	While,
 }
 
+/// Reserved words, an identifier is a keyword only if it's exactly one of these
+const KEYWORDS: &[(&str, TokenKind<'static>)] = &[
+	("and", TokenKind::And),
+	("break", TokenKind::Break),
+	("catch", TokenKind::Catch),
+	("class", TokenKind::Class),
+	("continue", TokenKind::Continue),
+	("div", TokenKind::Div),
+	("do", TokenKind::Do),
+	("else", TokenKind::Else),
+	("false", TokenKind::False),
+	("for", TokenKind::For),
+	("fun", TokenKind::Fun),
+	("if", TokenKind::If),
+	("import", TokenKind::Import),
+	("is", TokenKind::Is),
+	("match", TokenKind::Match),
+	("mod", TokenKind::Mod),
+	("nil", TokenKind::Nil),
+	("or", TokenKind::Or),
+	("print", TokenKind::Print),
+	("repeat", TokenKind::Repeat),
+	("return", TokenKind::Return),
+	("super", TokenKind::Super),
+	("this", TokenKind::This),
+	("throw", TokenKind::Throw),
+	("true", TokenKind::True),
+	("try", TokenKind::Try),
+	("var", TokenKind::Var),
+	("while", TokenKind::While),
+];
+
 #[derive(Debug, Clone)]
 pub struct Token<'a> {
 	pub kind: TokenKind<'a>,
@@ -63,6 +113,12 @@ pub struct Error {
 	lexeme: String,
 }
 
+impl Error {
+	pub fn line(&self) -> usize {
+		self.line
+	}
+}
+
 #[derive(Debug, Error)]
 pub enum ErrorKind {
 	#[error("Unexpected character: {0}")]
@@ -71,6 +127,7 @@ pub enum ErrorKind {
 	UnterminatedString,
 }
 
+#[derive(Clone)]
 pub struct Scanner<'a> {
 	source: &'a str,
 	start: usize,
@@ -105,7 +162,16 @@ impl<'a> Scanner<'a> {
 			b'-' => Some(Ok(self.make_token(TokenKind::Minus))),
 			b'+' => Some(Ok(self.make_token(TokenKind::Plus))),
 			b'/' => Some(Ok(self.make_token(TokenKind::Slash))),
-			b'*' => Some(Ok(self.make_token(TokenKind::Star))),
+			b'*' => {
+				let kind = if self.matches(b'*') {
+					TokenKind::StarStar
+				} else {
+					TokenKind::Star
+				};
+				Some(Ok(self.make_token(kind)))
+			}
+			b'%' => Some(Ok(self.make_token(TokenKind::Percent))),
+			b':' => Some(Ok(self.make_token(TokenKind::Colon))),
 
 			b'!' => {
 				let kind = if self.matches(b'=') {
@@ -119,6 +185,8 @@ impl<'a> Scanner<'a> {
 			b'=' => {
 				let kind = if self.matches(b'=') {
 					TokenKind::EqualEqual
+				} else if self.matches(b'>') {
+					TokenKind::EqualGreater
 				} else {
 					TokenKind::Equal
 				};
@@ -143,6 +211,15 @@ impl<'a> Scanner<'a> {
 				Some(Ok(self.make_token(kind)))
 			}
 
+			b'?' => {
+				let kind = if self.matches(b'?') {
+					TokenKind::QuestionQuestion
+				} else {
+					TokenKind::Question
+				};
+				Some(Ok(self.make_token(kind)))
+			}
+
 			b'"' => Some(self.string().map(|k| self.make_token(k))),
 
 			c if c.is_ascii_digit() => Some(self.number().map(|k| self.make_token(k))),
@@ -186,6 +263,8 @@ impl<'a> Scanner<'a> {
 	}
 
 	fn string(&mut self) -> Result<TokenKind<'a>, Error> {
+		// Reported instead of the line at EOF, which for a runaway string can be far away
+		let opening_line = self.line;
 		loop {
 			match self.peek() {
 				Some(b'"') => {
@@ -198,7 +277,14 @@ impl<'a> Scanner<'a> {
 					}
 					self.advance();
 				}
-				None => return Err(self.make_error(ErrorKind::UnterminatedString)),
+				None => {
+					let first_line = self.source[self.start..].lines().next();
+					return Err(Error {
+						err: ErrorKind::UnterminatedString,
+						line: opening_line,
+						lexeme: first_line.unwrap_or_default().to_string(),
+					});
+				}
 			}
 		}
 
@@ -237,36 +323,12 @@ impl<'a> Scanner<'a> {
 	}
 
 	fn identifier_kind(&self) -> TokenKind<'a> {
-		let ident = &self.source[self.start..=(self.current - 1)];
-		let rest = &ident[1..];
-		match ident.as_bytes()[0] {
-			b'a' if rest == "nd" => TokenKind::And,
-			b'c' if rest == "lass" => TokenKind::Class,
-			b'e' if rest == "lse" => TokenKind::Else,
-			b'i' if rest == "f" => TokenKind::If,
-			b'n' if rest == "il" => TokenKind::Nil,
-			b'o' if rest == "r" => TokenKind::Or,
-			b'p' if rest == "rint" => TokenKind::Print,
-			b'r' if rest == "eturn" => TokenKind::Return,
-			b's' if rest == "uper" => TokenKind::Super,
-			b'v' if rest == "ar" => TokenKind::Var,
-			b'w' if rest == "hile" => TokenKind::While,
-			b'f' if ident.len() > 1 => match ident.as_bytes()[1] {
-				b'a' if &ident[2..] == "lse" => TokenKind::False,
-				b'o' if &ident[2..] == "r" => TokenKind::For,
-				b'u' if &ident[2..] == "n" => TokenKind::Fun,
-				_ => TokenKind::Identifier(ident),
-			},
-			b't' if ident.len() > 1 => match ident.as_bytes()[1] {
-				b'h' if &ident[2..] == "is" => TokenKind::This,
-				b'r' if &ident[2..] == "ue" => TokenKind::True,
-				_ => TokenKind::Identifier(ident),
-			},
-			_ => {
-				let ident = &self.source[self.start..self.current];
-				TokenKind::Identifier(ident)
-			}
-		}
+		let ident = &self.source[self.start..self.current];
+		KEYWORDS
+			.iter()
+			.find(|(keyword, _)| *keyword == ident)
+			.map(|(_, kind)| *kind)
+			.unwrap_or(TokenKind::Identifier(ident))
 	}
 
 	fn skip_whitespace(&mut self) {
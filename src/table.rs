@@ -7,8 +7,40 @@ use crate::value::Value;
 /// Hand rolled HashMap<ObjString, Value>
 pub struct Table {
 	entries: *mut Entry,
+	/// Number of occupied entries, including tombstones
 	len: usize,
+	tombstones: usize,
 	capacity: usize,
+	config: TableConfig,
+}
+
+/// When a [Table] resizes. Lower loads mean fewer collisions at the cost of more memory.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TableConfig {
+	/// The table grows once setting a key would make it fuller than this, tombstones included.
+	/// Must be between 0 and 1, exclusive.
+	pub max_load: f64,
+	/// Once the live entries drop below this load the table shrinks to half its size. Must be below
+	/// `max_load / 2` so the entries still fit after shrinking. Keeping it well below
+	/// `max_load / grow_factor` stops a table hovering around a threshold from resizing back and
+	/// forth.
+	pub min_load: f64,
+	/// How many times larger the table gets when it grows, at least 2
+	pub grow_factor: usize,
+}
+
+impl TableConfig {
+	pub const DEFAULT: TableConfig = TableConfig {
+		max_load: 0.75,
+		min_load: 0.25,
+		grow_factor: 2,
+	};
+}
+
+impl Default for TableConfig {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
 }
 
 unsafe impl Sync for Table {}
@@ -33,14 +65,61 @@ impl Default for Table {
 }
 
 impl Table {
-	const MAX_LOAD: f64 = 0.75;
-
 	pub const fn new() -> Self {
+		Self::with_config(TableConfig::DEFAULT)
+	}
+
+	/// # Panics
+	///
+	/// If `config` is out of the ranges documented on [TableConfig]
+	pub const fn with_config(config: TableConfig) -> Self {
+		assert!(
+			config.max_load > 0.0 && config.max_load < 1.0,
+			"max_load must be between 0 and 1"
+		);
+		assert!(
+			config.min_load >= 0.0 && config.min_load * 2.0 < config.max_load,
+			"min_load must be between 0 and half of max_load"
+		);
+		assert!(config.grow_factor >= 2, "grow_factor must be at least 2");
 		Table {
 			entries: ptr::null_mut(),
 			len: 0,
+			tombstones: 0,
 			capacity: 0,
+			config,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len - self.tombstones
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Number of bytes allocated for the entries
+	pub fn allocated_bytes(&self) -> usize {
+		self.capacity * std::mem::size_of::<Entry>()
+	}
+
+	/// Grows the table so that `additional` more keys can be set without resizing it again
+	pub fn reserve(&mut self, additional: usize) {
+		let needed = self.len + additional;
+		let fits = |capacity: usize| ((capacity as f64) * self.config.max_load) as usize >= needed;
+		if fits(self.capacity) {
+			return;
+		}
+		let mut capacity = self.grow_capacity(self.capacity);
+		while !fits(capacity) {
+			capacity = self.grow_capacity(capacity);
 		}
+		self.adjust_capacity(capacity);
 	}
 
 	pub fn get(&mut self, key: *mut ObjString) -> Option<&Value> {
@@ -59,16 +138,20 @@ impl Table {
 	}
 
 	pub fn set(&mut self, key: *mut ObjString, value: Value) -> bool {
-		if self.len + 1 > ((self.capacity as f64) * Table::MAX_LOAD) as usize {
-			let capacity = grow_capacity(self.capacity);
+		if self.len + 1 > ((self.capacity as f64) * self.config.max_load) as usize {
+			let capacity = self.grow_capacity(self.capacity);
 			self.adjust_capacity(capacity);
 		}
 
 		let entry = find_entry(self.entries, self.capacity, key);
 		unsafe {
 			let is_new_key = (*entry).key.is_null();
-			if is_new_key && (*entry).value == Value::Nil {
-				self.len += 1;
+			if is_new_key {
+				if (*entry).value == Value::Nil {
+					self.len += 1;
+				} else {
+					self.tombstones -= 1;
+				}
 			}
 
 			(*entry).key = key;
@@ -84,13 +167,22 @@ impl Table {
 		}
 
 		let entry = find_entry(self.entries, self.capacity, key);
-		if entry.is_null() {
-			return false;
-		}
 		unsafe {
+			if (*entry).key.is_null() {
+				return false;
+			}
 			(*entry).key = ptr::null_mut();
 			(*entry).value = Value::Bool(true);
 		}
+		self.tombstones += 1;
+
+		let live = self.len - self.tombstones;
+		if self.capacity > MIN_CAPACITY
+			&& (live as f64) < (self.capacity as f64) * self.config.min_load
+		{
+			self.adjust_capacity(shrink_capacity(self.capacity));
+		}
+
 		true
 	}
 
@@ -98,7 +190,7 @@ impl Table {
 		for i in 0..self.capacity {
 			let entry = unsafe { &mut *self.entries.add(i) };
 			if entry.key.is_null() {
-				dest.set(entry.key, entry.value.clone());
+				dest.set(entry.key, entry.value);
 			}
 		}
 	}
@@ -109,7 +201,8 @@ impl Table {
 		}
 
 		let str = str.as_ref();
-		let mut idx = (hash as usize) % self.capacity;
+		// `None` rather than dividing by zero if nothing was ever allocated
+		let mut idx = (hash as usize).checked_rem(self.capacity)?;
 		loop {
 			unsafe {
 				let entry = self.entries.add(idx);
@@ -117,7 +210,7 @@ impl Table {
 					if matches!((*entry).value, Value::Nil) {
 						return None;
 					}
-				} else if (*(*entry).key).len() == str.len()
+				} else if (*(*entry).key).byte_len() == str.len()
 					&& (*(*entry).key).hash == hash
 					&& (*(*entry).key).as_str() == str
 				{
@@ -129,6 +222,14 @@ impl Table {
 		}
 	}
 
+	const fn grow_capacity(&self, capacity: usize) -> usize {
+		if capacity < MIN_CAPACITY {
+			MIN_CAPACITY
+		} else {
+			capacity * self.config.grow_factor
+		}
+	}
+
 	fn adjust_capacity(&mut self, new_capacity: usize) {
 		let entries = allocate_array::<Entry>(new_capacity);
 		for i in 0..new_capacity {
@@ -141,6 +242,7 @@ impl Table {
 		}
 
 		self.len = 0;
+		self.tombstones = 0;
 		for i in 0..self.capacity {
 			let entry = unsafe { &mut *self.entries.add(i) };
 			if entry.key.is_null() {
@@ -195,11 +297,13 @@ fn find_entry(entries: *mut Entry, capacity: usize, key: *mut ObjString) -> *mut
 	}
 }
 
-const fn grow_capacity(capacity: usize) -> usize {
-	if capacity < 8 {
-		8
+const MIN_CAPACITY: usize = 8;
+
+const fn shrink_capacity(capacity: usize) -> usize {
+	if capacity / 2 < MIN_CAPACITY {
+		MIN_CAPACITY
 	} else {
-		capacity * 2
+		capacity / 2
 	}
 }
 
@@ -219,7 +323,7 @@ pub fn free_array<T>(array: *mut T, capacity: usize) {
 
 #[cfg(test)]
 mod tests {
-	use super::Table;
+	use super::{hash, Table, TableConfig, MIN_CAPACITY};
 	use crate::object::{Allocator, ObjString};
 	use crate::value::Value;
 
@@ -229,13 +333,16 @@ mod tests {
 		let mut table = Table::default();
 
 		{
-			let key = allocator.take_string("ala".to_string()).cast::<ObjString>();
+			let key = allocator
+				.take_string("ala".to_string())
+				.unwrap()
+				.cast::<ObjString>();
 
-			let value = Value::Object(allocator.take_string("ma kota".to_string()));
+			let value = Value::Object(allocator.take_string("ma kota".to_string()).unwrap());
 			table.set(key, value);
 		}
 
-		let key = allocator.copy_string("ala").cast::<ObjString>();
+		let key = allocator.copy_string("ala").unwrap().cast::<ObjString>();
 		let value = table.get(key);
 		match value {
 			Some(Value::Object(value)) => unsafe {
@@ -244,4 +351,125 @@ mod tests {
 			_ => panic!("unexpected value {value:?}"),
 		}
 	}
+
+	#[test]
+	fn shrinks_after_deletes() {
+		let mut allocator = Allocator::default();
+		let mut table = Table::default();
+
+		let keys: Vec<*mut ObjString> = (0..100)
+			.map(|i| {
+				allocator
+					.take_string(format!("key{i}"))
+					.unwrap()
+					.cast::<ObjString>()
+			})
+			.collect();
+		for (i, key) in keys.iter().enumerate() {
+			table.set(*key, Value::Number(i as f64));
+		}
+		let full_capacity = table.capacity;
+
+		for key in &keys[..95] {
+			assert!(table.delete(*key));
+		}
+		assert!(!table.delete(keys[0]));
+
+		assert!(table.capacity < full_capacity);
+		assert!(table.capacity >= MIN_CAPACITY);
+		for (i, key) in keys.iter().enumerate().skip(95) {
+			assert_eq!(table.get(*key), Some(&Value::Number(i as f64)));
+		}
+		assert_eq!(table.get(keys[0]), None);
+	}
+
+	#[test]
+	fn lower_max_load_grows_earlier() {
+		let mut allocator = Allocator::default();
+		let mut default = Table::default();
+		let mut sparse = Table::with_config(TableConfig {
+			max_load: 0.25,
+			min_load: 0.05,
+			grow_factor: 4,
+		});
+
+		for i in 0..7 {
+			let key = allocator
+				.take_string(format!("key{i}"))
+				.unwrap()
+				.cast::<ObjString>();
+			default.set(key, Value::Number(i as f64));
+			sparse.set(key, Value::Number(i as f64));
+		}
+
+		// 8 entries hold 6 keys by default and the 7th doubles them. With a load of 0.25 they
+		// hold only 2 and the 3rd grows them 4 times.
+		assert_eq!(default.capacity(), 16);
+		assert_eq!(sparse.capacity(), 32);
+		assert!(sparse.allocated_bytes() > default.allocated_bytes());
+	}
+
+	#[test]
+	#[should_panic(expected = "min_load must be between 0 and half of max_load")]
+	fn min_load_above_half_of_max_load_is_rejected() {
+		// Shrinking a table at a load of 0.8 by half would leave it with more entries than slots
+		Table::with_config(TableConfig {
+			max_load: 0.9,
+			min_load: 0.8,
+			grow_factor: 2,
+		});
+	}
+
+	#[test]
+	fn highest_min_load_survives_deletes() {
+		let mut allocator = Allocator::default();
+		let mut table = Table::with_config(TableConfig {
+			max_load: 0.9,
+			min_load: 0.44,
+			grow_factor: 2,
+		});
+		let keys: Vec<*mut ObjString> = (0..100)
+			.map(|i| {
+				allocator
+					.take_string(format!("key{i}"))
+					.unwrap()
+					.cast::<ObjString>()
+			})
+			.collect();
+		for (i, key) in keys.iter().enumerate() {
+			table.set(*key, Value::Number(i as f64));
+		}
+		for (i, key) in keys.iter().enumerate().rev() {
+			assert_eq!(table.get(*key), Some(&Value::Number(i as f64)));
+			assert!(table.delete(*key));
+			assert!(table.len() <= table.capacity());
+		}
+		assert!(table.is_empty());
+	}
+
+	#[test]
+	fn find_string_in_empty_table() {
+		let mut table = Table::default();
+		assert_eq!(table.capacity, 0);
+		assert_eq!(table.find_string("ala", hash("ala")), None);
+
+		let mut allocator = Allocator::default();
+		let keys: Vec<*mut ObjString> = (0..20)
+			.map(|i| {
+				allocator
+					.take_string(format!("key{i}"))
+					.unwrap()
+					.cast::<ObjString>()
+			})
+			.collect();
+		for key in &keys {
+			table.set(*key, Value::Nil);
+		}
+		for key in &keys {
+			table.delete(*key);
+		}
+		assert!(table.is_empty());
+		assert!(table.capacity > 0);
+		assert_eq!(table.find_string("key0", hash("key0")), None);
+	}
 }
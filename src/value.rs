@@ -1,7 +1,8 @@
-use crate::object::Object;
+use crate::object::{ObjForeign, ObjString, Object, ObjectKind};
+use std::any::Any;
 use std::fmt::{Display, Formatter};
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Copy, Debug)]
 pub enum Value {
 	#[default]
 	Nil,
@@ -11,18 +12,161 @@ pub enum Value {
 }
 
 impl Value {
+	/// Lox's truthiness rule, used by `!` and conditions: `nil` and `false` are falsey,
+	/// everything else is truthy, including `0` and `""`
+	pub fn is_truthy(&self) -> bool {
+		!matches!(self, Self::Nil | Self::Bool(false))
+	}
+
 	pub fn is_falsey(&self) -> bool {
-		matches!(self, Self::Nil | Self::Bool(false))
+		!self.is_truthy()
+	}
+
+	/// The string this value points to, `None` if it's not a string
+	///
+	/// # Safety
+	///
+	/// The object the value points to has to be alive. A value is a copy of a pointer and can
+	/// outlive its object, which is freed along with the VM or earlier by
+	/// [Vm::reset_all](crate::vm::Vm::reset_all). Values on the stack of a running VM and
+	/// arguments passed to natives are alive.
+	pub unsafe fn as_obj_string(&self) -> Option<&ObjString> {
+		match self {
+			Self::Object(obj) => (**obj).downcast(),
+			_ => None,
+		}
+	}
+
+	/// Contents of the string this value points to, `None` if it's not a string. Hosts holding on
+	/// to values get the contents safely with [Vm::string](crate::vm::Vm::string).
+	///
+	/// # Safety
+	///
+	/// Same as [Value::as_obj_string]
+	pub unsafe fn as_str(&self) -> Option<&str> {
+		self.as_obj_string().map(ObjString::as_str)
+	}
+
+	/// The host value wrapped by [Vm::make_foreign], `None` if this isn't a foreign value or it
+	/// wraps something other than a `T`. Hosts holding on to values get it safely with
+	/// [Vm::foreign].
+	///
+	/// # Safety
+	///
+	/// Same as [Value::as_obj_string]
+	///
+	/// [Vm::make_foreign]: crate::vm::Vm::make_foreign
+	/// [Vm::foreign]: crate::vm::Vm::foreign
+	pub unsafe fn as_foreign<T: Any>(&self) -> Option<&T> {
+		match self {
+			Self::Object(obj) => (**obj).downcast::<ObjForeign>()?.downcast_ref(),
+			_ => None,
+		}
+	}
+
+	pub fn as_f64(&self) -> Option<f64> {
+		match self {
+			Self::Number(n) => Some(*n),
+			_ => None,
+		}
+	}
+
+	/// Hosts holding on to values get the type safely with [Vm::type_of].
+	///
+	/// # Safety
+	///
+	/// Same as [Value::as_obj_string]
+	///
+	/// [Vm::type_of]: crate::vm::Vm::type_of
+	pub unsafe fn type_of(&self) -> Type {
+		match self {
+			Self::Nil => Type::Nil,
+			Self::Bool(_) => Type::Bool,
+			Self::Number(_) => Type::Number,
+			Self::Object(obj) => match (**obj).kind {
+				ObjectKind::String => Type::String,
+				ObjectKind::Native => Type::Function,
+				ObjectKind::Foreign => Type::Foreign,
+			},
+		}
+	}
+
+	/// The bool this value holds, `None` for other types. Not the same as truthiness, see
+	/// [Value::is_falsey].
+	pub fn as_bool(&self) -> Option<bool> {
+		match self {
+			Self::Bool(b) => Some(*b),
+			_ => None,
+		}
+	}
+}
+
+/// Built-in types, the right hand side of the `is` operator
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Type {
+	Nil,
+	Bool,
+	Number,
+	String,
+	Function,
+	Foreign,
+}
+
+impl Type {
+	/// Every type, indexed by its byte
+	pub const ALL: &'static [Type] = &[
+		Type::Nil,
+		Type::Bool,
+		Type::Number,
+		Type::String,
+		Type::Function,
+		Type::Foreign,
+	];
+
+	/// How the type is written in source, e.g. `Number`
+	pub fn name(self) -> &'static str {
+		match self {
+			Type::Nil => "Nil",
+			Type::Bool => "Bool",
+			Type::Number => "Number",
+			Type::String => "String",
+			Type::Function => "Function",
+			Type::Foreign => "Foreign",
+		}
+	}
+
+	pub fn from_name(name: &str) -> Option<Type> {
+		Type::ALL.iter().copied().find(|ty| ty.name() == name)
+	}
+}
+
+impl Display for Type {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.pad(self.name())
 	}
 }
 
+/// Values of different types are never equal: `nil == false` and `"1" == 1` are both false.
+/// There's a single number type, so `1 == 1.0`. Objects compare by identity, except for strings
+/// which compare by contents. Interned strings are equal only if they're the same object, so
+/// comparing contents is needed only when interning is off, see [Allocator::set_interning].
+///
+/// [Allocator::set_interning]: crate::object::Allocator::set_interning
 impl PartialEq for Value {
 	fn eq(&self, other: &Self) -> bool {
 		match (self, other) {
 			(Value::Nil, Value::Nil) => true,
 			(Value::Bool(a), Value::Bool(b)) => a == b,
 			(Value::Number(a), Value::Number(b)) => a == b,
-			(Value::Object(a), Value::Object(b)) => std::ptr::eq(a, b),
+			(Value::Object(a), Value::Object(b)) if std::ptr::eq(*a, *b) => true,
+			// Like printing, comparing expects both objects to be alive
+			(Value::Object(_), Value::Object(_)) => {
+				match unsafe { (self.as_obj_string(), other.as_obj_string()) } {
+					(Some(a), Some(b)) => a.hash == b.hash && a.as_str() == b.as_str(),
+					_ => false,
+				}
+			}
 			_ => false,
 		}
 	}
@@ -33,7 +177,7 @@ impl Display for Value {
 		match self {
 			Self::Nil => write!(f, "nil"),
 			Self::Bool(b) => std::fmt::Display::fmt(b, f),
-			Self::Number(n) => std::fmt::Display::fmt(n, f),
+			Self::Number(n) => fmt_number(*n, f),
 			Self::Object(o) => unsafe {
 				let o: &Object = &**o;
 				Display::fmt(o, f)
@@ -42,6 +186,46 @@ impl Display for Value {
 	}
 }
 
+/// Formats `n` like clox does with C's `printf("%g", n)`: 6 significant digits without trailing
+/// zeros, in scientific notation when the exponent is below -4 or above 5. `nan`, `inf` and `-inf`
+/// for non-finite numbers.
+fn fmt_number(n: f64, f: &mut Formatter<'_>) -> std::fmt::Result {
+	const PRECISION: i32 = 6;
+
+	if n.is_nan() {
+		return f.pad("nan");
+	}
+	if n.is_infinite() {
+		return f.pad(if n > 0.0 { "inf" } else { "-inf" });
+	}
+	if n == 0.0 {
+		return f.pad(if n.is_sign_negative() { "-0" } else { "0" });
+	}
+
+	// The exponent has to be taken after rounding, 999999.5 rounds up to 1e+06
+	let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+	let (mantissa, exponent) = scientific
+		.split_once('e')
+		.expect("LowerExp output has an 'e'");
+	let exponent: i32 = exponent.parse().expect("LowerExp exponent is a number");
+
+	if (-4..PRECISION).contains(&exponent) {
+		let decimals = (PRECISION - 1 - exponent) as usize;
+		f.pad(trim_fraction_zeros(&format!("{n:.decimals$}")))
+	} else {
+		let sign = if exponent < 0 { '-' } else { '+' };
+		let mantissa = trim_fraction_zeros(mantissa);
+		f.pad(&format!("{mantissa}e{sign}{:02}", exponent.abs()))
+	}
+}
+
+fn trim_fraction_zeros(s: &str) -> &str {
+	match s.contains('.') {
+		true => s.trim_end_matches('0').trim_end_matches('.'),
+		false => s,
+	}
+}
+
 impl From<f64> for Value {
 	fn from(n: f64) -> Self {
 		Value::Number(n)
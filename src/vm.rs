@@ -1,28 +1,53 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::Write as _;
 use std::io::{Stdout, Write};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
-use crate::object::ObjString;
+mod dispatch;
+
+use crate::native::{self, NativeFn, Rng};
+use crate::object::{ObjForeign, ObjNative, ObjString};
 use crate::{
-	chunk::{Chunk, InstructionKind, OpCode},
+	chunk::{Chunk, Fused, Instruction, InstructionKind, OpCode},
 	compiler,
-	object::{self, Object, ObjectKind},
-	table::Table,
-	value::Value,
+	globals::{Globals, Snapshot},
+	object,
+	value::{Type, Value},
 };
 
+/// Wraps `source` with the line of the instruction at `offset`. Never panics, so that corrupt
+/// bytecode with a short line table still gets reported as an error.
+fn runtime_error(chunk: &Chunk, offset: usize, source: RuntimeError) -> InterpretError {
+	InterpretError::Runtime {
+		source,
+		line: chunk.line(offset),
+	}
+}
+
+const LOCAL_OUT_OF_RANGE: RuntimeError = RuntimeError::InvalidBytecode("local slot out of range");
+const GLOBAL_OUT_OF_RANGE: RuntimeError = RuntimeError::InvalidBytecode("global slot out of range");
+
 #[derive(Debug, Error)]
 pub enum InterpretError {
-	#[error("Compile: {0}")]
-	Compile(#[from] compiler::Error),
-
-	#[error("Runtime error")]
-	GenericRuntime,
+	#[error("[line {line}] Compile: {source}")]
+	Compile {
+		source: compiler::Error,
+		line: usize,
+	},
 
 	#[error("[line {line}] {source}")]
 	Runtime { source: RuntimeError, line: usize },
 
 	#[error(transparent)]
-	UnknownOpCode(#[from] crate::chunk::UnknownOpCode),
+	Decode(#[from] crate::chunk::DecodeError),
+
+	#[error("Failed to write execution trace")]
+	Trace(#[from] std::fmt::Error),
+
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -33,8 +58,48 @@ pub enum RuntimeError {
 	#[error(transparent)]
 	InvalidTypes(InvalidTypesError),
 
+	#[error("Stack underflow.")]
+	StackUnderflow,
+
+	/// The chunk wasn't produced by the compiler, or the compiler has a bug
+	#[error("Invalid bytecode: {0}.")]
+	InvalidBytecode(&'static str),
+
 	#[error("Undefined variable '{0}'.")]
 	UndefinedVariable(String),
+
+	#[error(transparent)]
+	AllocationLimitExceeded(#[from] object::AllocationLimitExceeded),
+
+	#[error("Uncaught exception: {0}")]
+	Uncaught(Value),
+
+	#[error("Stack overflow")]
+	StackOverflow,
+
+	#[error("Can only call functions")]
+	NotCallable,
+
+	#[error("Expected {expected} arguments but got {got}")]
+	Arity { expected: usize, got: usize },
+
+	/// Error raised by a native function
+	#[error("{0}")]
+	Native(String),
+
+	/// `assertEq` or `assertNeq` failed. The values are rendered with strings quoted.
+	#[error("Assertion failed: {left} {op} {right}")]
+	AssertionFailed {
+		left: String,
+		op: &'static str,
+		right: String,
+	},
+
+	#[error("Failed to import '{path}': {source}")]
+	Import {
+		path: String,
+		source: Box<InterpretError>,
+	},
 }
 
 #[derive(Debug, Error)]
@@ -58,16 +123,103 @@ pub enum InvalidTypeErrorKind {
 
 	#[error("Operands must be two numbers or two strings")]
 	ExpectedNumberOrStringOperand,
+
+	#[error("Argument must be a number")]
+	ExpectedNumberArgument,
+
+	#[error("Argument must be a string")]
+	ExpectedStringArgument,
+
+	#[error("Argument must be a whole number")]
+	ExpectedIntegerArgument,
+
+	#[error("Repeat count must be a non-negative whole number")]
+	ExpectedRepeatCount,
+}
+
+/// Maximum number of values on the stack. It's reserved up front and never reallocated, so
+/// pointers into it stay valid for the VM's lifetime.
+pub const STACK_MAX: usize = 64 * 256;
+
+/// Most values a single instruction pushes onto the stack
+const MAX_PUSHES: usize = 2;
+
+/// How [Vm::run] picks the code for each instruction
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+	/// Decode the instruction and `match` on it
+	#[default]
+	Match,
+	/// Index a table of handler functions with the opcode byte. Experimental, kept around to
+	/// compare the performance of both approaches (see `benches/dispatch.rs`).
+	Table,
 }
 
+/// Lox source of the prelude loaded by [Vm::with_prelude]
+pub const DEFAULT_PRELUDE: &str = include_str!("prelude.lox");
+
+/// An exception handler installed by a `try` block
+struct Handler {
+	/// Offset of the catch block
+	catch_ip: usize,
+	/// Stack length to unwind to when a value is thrown
+	stack_len: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryStats {
+	/// Bytes held by heap objects, see [object::Allocator::bytes_allocated]
+	pub bytes_allocated: usize,
+	pub object_count: usize,
+	/// Bytes reserved for the value stack
+	pub stack_bytes: usize,
+	/// Bytes reserved for the globals table
+	pub globals_bytes: usize,
+	pub globals_count: usize,
+}
+
+/// See [Vm::on_print]
+pub type PrintHook = Box<dyn FnMut(&Value)>;
+
+/// See [Vm::on_missing_global]
+pub type MissingGlobalHook = Box<dyn FnMut(&str) -> Option<Value>>;
+
 pub struct Vm<W> {
 	pub debug: bool,
+	pub dispatch: Dispatch,
+	/// Fuse common instruction pairs after compiling, see [Chunk::fuse_superinstructions]
+	pub superinstructions: bool,
+	/// Let `+` concatenate a string with a number or a bool, stringifying the latter. Off by
+	/// default, mixing types in `+` is a runtime error.
+	pub coerce_concat: bool,
+	/// How deeply scripts can nest statements and expressions, see
+	/// [compiler::Options::max_nesting]
+	pub max_nesting: usize,
+	/// Let line breaks end statements in place of `;`, see
+	/// [compiler::Options::newline_terminators]
+	pub newline_terminators: bool,
 
 	stack: Vec<Value>,
 	objects: object::Allocator,
-	globals: Table,
+	globals: Globals,
+	/// Chunk compiled by the latest [Vm::interpret] call, kept for inspection
+	last_chunk: Option<Chunk>,
 
 	stdout: W,
+	trace: Option<Box<dyn std::fmt::Write>>,
+	/// Where compiler warnings go, stderr if not set
+	warnings: Option<Box<dyn std::fmt::Write>>,
+	/// Called with every printed value, see [Vm::on_print]
+	print_hook: Option<PrintHook>,
+	/// Supplies globals that aren't defined, see [Vm::on_missing_global]
+	missing_global_hook: Option<MissingGlobalHook>,
+
+	/// Directory relative imports are resolved against
+	base_path: Option<PathBuf>,
+	/// Canonical paths of files that were already run, each file is imported only once
+	imported: HashSet<PathBuf>,
+
+	rng: Rng,
 }
 
 impl Default for Vm<Stdout> {
@@ -80,38 +232,356 @@ impl<W: Write> Vm<W> {
 	pub fn new(stdout: W) -> Vm<W> {
 		Vm {
 			debug: false,
-			stack: Vec::new(),
+			dispatch: Dispatch::default(),
+			superinstructions: false,
+			coerce_concat: false,
+			max_nesting: compiler::DEFAULT_MAX_NESTING,
+			newline_terminators: false,
+			stack: Vec::with_capacity(STACK_MAX),
 			objects: Default::default(),
 			globals: Default::default(),
+			last_chunk: None,
 			stdout,
+			trace: None,
+			warnings: None,
+			print_hook: None,
+			missing_global_hook: None,
+			base_path: None,
+			imported: HashSet::new(),
+			rng: Rng::new(
+				std::time::SystemTime::now()
+					.duration_since(std::time::UNIX_EPOCH)
+					.map(|time| time.as_nanos() as u64)
+					.unwrap_or_default(),
+			),
 		}
 	}
 
+	/// Defines `random()`, `randomInt(lo, hi)`, `abs(x)`, `min(x, ...)`, `max(x, ...)`,
+	/// `format(fmt, ...)`, `ord(s)` and `chr(n)`
+	pub fn with_std_natives(mut self) -> Self {
+		self.define_native("random", Some(0), native::random)
+			.expect("failed to define random");
+		self.define_native("randomInt", Some(2), native::random_int)
+			.expect("failed to define randomInt");
+		self.define_native("abs", Some(1), native::abs)
+			.expect("failed to define abs");
+		self.define_native("min", None, native::min)
+			.expect("failed to define min");
+		self.define_native("max", None, native::max)
+			.expect("failed to define max");
+		self.define_native("format", None, native::format)
+			.expect("failed to define format");
+		self.define_native("ord", Some(1), native::ord)
+			.expect("failed to define ord");
+		self.define_native("chr", Some(1), native::chr)
+			.expect("failed to define chr");
+		self.define_native("hex", Some(1), native::hex)
+			.expect("failed to define hex");
+		self.define_native("oct", Some(1), native::oct)
+			.expect("failed to define oct");
+		self.define_native("bin", Some(1), native::bin)
+			.expect("failed to define bin");
+		self.define_native("toFixed", Some(2), native::to_fixed)
+			.expect("failed to define toFixed");
+		self.define_native("assertEq", Some(2), native::assert_eq)
+			.expect("failed to define assertEq");
+		self.define_native("assertNeq", Some(2), native::assert_neq)
+			.expect("failed to define assertNeq");
+		self
+	}
+
+	/// Sizes the globals up front for `capacity` variables, so defining that many (natives and
+	/// the prelude included) doesn't repeatedly grow the table
+	pub fn with_globals_capacity(mut self, capacity: usize) -> Self {
+		self.globals.reserve(capacity);
+		self
+	}
+
+	/// Reseeds the generator behind `random` and `randomInt`. VMs seeded with the same value
+	/// produce the same sequence of numbers. By default the seed is taken from the current time.
+	pub fn seed_rng(&mut self, seed: u64) {
+		self.rng = Rng::new(seed);
+	}
+
+	/// Defines `readFile(path)`, `writeFile(path, contents)` and `getenv(name)`. These give
+	/// scripts access to the file system and environment, so they're not available by default.
+	pub fn with_io_natives(mut self) -> Self {
+		self.define_native("readFile", Some(1), native::read_file)
+			.expect("failed to define readFile");
+		self.define_native("writeFile", Some(2), native::write_file)
+			.expect("failed to define writeFile");
+		self.define_native("getenv", Some(1), native::getenv)
+			.expect("failed to define getenv");
+		self
+	}
+
+	/// Defines a global function `name` implemented by `function`. `arity` is the number of
+	/// arguments it takes, or `None` if it accepts any number of them.
+	pub fn define_native(
+		&mut self,
+		name: &str,
+		arity: Option<usize>,
+		function: NativeFn,
+	) -> Result<(), object::AllocationLimitExceeded> {
+		let native = self.objects.new_native(name, arity, function)?;
+		let name = self.objects.intern(name)?;
+		self.globals
+			.define(name.cast::<ObjString>(), Value::Object(native));
+		Ok(())
+	}
+
+	/// Defines a global variable `name`, for the host to hand values to scripts
+	pub fn define_global(
+		&mut self,
+		name: &str,
+		value: Value,
+	) -> Result<(), object::AllocationLimitExceeded> {
+		let name = self.objects.intern(name)?;
+		self.globals.define(name.cast::<ObjString>(), value);
+		Ok(())
+	}
+
+	/// Contents of the string `value` points to. `None` if it isn't a string, or if its object
+	/// isn't alive in this VM anymore, e.g. after [Vm::reset_all]. Checking that walks every
+	/// object.
+	pub fn string(&self, value: Value) -> Option<&str> {
+		match value {
+			// Alive, and it can't be freed while the VM is borrowed
+			Value::Object(obj) if self.objects.owns(obj) => {
+				unsafe { (*obj).downcast() }.map(ObjString::as_str)
+			}
+			_ => None,
+		}
+	}
+
+	/// The host value `value` wraps, see [Vm::make_foreign]. `None` if it isn't a foreign value
+	/// wrapping a `T`, or if its object isn't alive in this VM anymore, like with [Vm::string].
+	pub fn foreign<T: Any>(&self, value: Value) -> Option<&T> {
+		match value {
+			// Alive, and it can't be freed while the VM is borrowed
+			Value::Object(obj) if self.objects.owns(obj) => unsafe {
+				(*obj).downcast::<ObjForeign>()?.downcast_ref()
+			},
+			_ => None,
+		}
+	}
+
+	/// The type of `value`, `None` if its object isn't alive in this VM anymore, like with
+	/// [Vm::string]
+	pub fn type_of(&self, value: Value) -> Option<Type> {
+		match value {
+			Value::Object(obj) if !self.objects.owns(obj) => None,
+			// Not an object, or checked to be alive
+			_ => Some(unsafe { value.type_of() }),
+		}
+	}
+
+	/// Wraps `value` so it can be stored in Lox variables. Scripts can pass it around and compare
+	/// it by identity but not look into it, it prints as `<foreign type_name>`. The host gets it
+	/// back with [Vm::foreign].
+	pub fn make_foreign<T: Any>(
+		&mut self,
+		type_name: &str,
+		value: T,
+	) -> Result<Value, object::AllocationLimitExceeded> {
+		Ok(Value::Object(self.objects.new_foreign(type_name, value)?))
+	}
+
+	/// Loads [DEFAULT_PRELUDE]
+	pub fn with_prelude(mut self) -> Self {
+		self.load_prelude(DEFAULT_PRELUDE)
+			.expect("failed to load the default prelude");
+		self
+	}
+
+	/// Runs `source` so the globals it defines are available to every script interpreted afterwards
+	pub fn load_prelude(&mut self, source: &str) -> Result<(), InterpretError> {
+		self.interpret(source)?;
+		Ok(())
+	}
+
+	/// Writes an execution trace to `w`: the stack contents followed by the disassembled
+	/// instruction, for every executed instruction. This is the same trace `debug` prints to
+	/// stdout. Values are rendered by content, so the trace is deterministic across runs.
+	pub fn trace_to(&mut self, w: impl std::fmt::Write + 'static) {
+		self.trace = Some(Box::new(w));
+	}
+
+	/// Writes compiler warnings to `w` instead of stderr, one per line
+	pub fn warnings_to(&mut self, w: impl std::fmt::Write + 'static) {
+		self.warnings = Some(Box::new(w));
+	}
+
+	/// Calls `hook` with every value `print` outputs, in order, before it's written to stdout. To
+	/// only capture the values, create the VM with [std::io::sink] as its stdout.
+	pub fn on_print(&mut self, hook: impl FnMut(&Value) + 'static) {
+		self.print_hook = Some(Box::new(hook));
+	}
+
+	/// Calls `hook` with the name of a global that's read before it's defined. The value it returns
+	/// is defined under that name, so the hook is asked at most once per name. `None` leaves the
+	/// variable undefined and the read fails as usual. Objects the hook returns have to come from
+	/// this VM, e.g. [Vm::make_foreign].
+	pub fn on_missing_global(&mut self, hook: impl FnMut(&str) -> Option<Value> + 'static) {
+		self.missing_global_hook = Some(Box::new(hook));
+	}
+
+	/// Limits the number of objects scripts can keep alive at once, see
+	/// [object::Allocator::set_max_objects].
+	pub fn set_max_objects(&mut self, max_objects: Option<usize>) {
+		self.objects.set_max_objects(max_objects);
+	}
+
+	/// Turns string interning on or off, see [object::Allocator::set_interning]
+	pub fn set_interning(&mut self, interning: bool) {
+		self.objects.set_interning(interning);
+	}
+
+	pub fn intern_stats(&self) -> object::InternStats {
+		self.objects.intern_stats()
+	}
+
+	pub fn memory_stats(&self) -> MemoryStats {
+		MemoryStats {
+			bytes_allocated: self.objects.bytes_allocated(),
+			object_count: self.objects.object_count(),
+			stack_bytes: self.stack.capacity() * std::mem::size_of::<Value>(),
+			globals_bytes: self.globals.allocated_bytes(),
+			globals_count: self.globals.len(),
+		}
+	}
+
+	/// Compiles and runs `source`. Returns the value of the final expression statement if the
+	/// script ends with one (`1 + 2` gives 3), `nil` otherwise.
 	pub fn interpret(&mut self, source: &str) -> Result<Value, InterpretError> {
+		let mut chunk = self.compile(source)?;
+		let result = self.run(&mut chunk);
+		self.last_chunk = Some(chunk);
+		result
+	}
+
+	/// Compiles `source` without running it. Its strings and globals belong to this VM, so the
+	/// chunk can be run with [Vm::run], once or many times.
+	pub fn compile(&mut self, source: &str) -> Result<Chunk, InterpretError> {
 		let mut chunk = Chunk::default();
-		compiler::compile(source, &mut chunk, self.debug, &mut self.objects)?;
-		self.run(&mut chunk)
+		let options = compiler::Options {
+			max_nesting: self.max_nesting,
+			newline_terminators: self.newline_terminators,
+			..compiler::Options::default()
+		};
+		let warnings = compiler::compile_located(
+			source,
+			&mut chunk,
+			self.debug,
+			&mut self.objects,
+			&mut self.globals,
+			options,
+		)
+		.map_err(|(source, line)| InterpretError::Compile { source, line })?;
+		for warning in warnings {
+			match &mut self.warnings {
+				Some(w) => writeln!(w, "{warning}")?,
+				None => eprintln!("{warning}"),
+			}
+		}
+		if self.superinstructions {
+			chunk.fuse_superinstructions();
+		}
+		Ok(chunk)
+	}
+
+	/// Clears the stack and globals and frees every object, as if the VM was just created. Settings
+	/// like [Vm::debug] and the output are kept, natives and the prelude have to be defined again.
+	pub fn reset_all(&mut self) {
+		self.stack.clear();
+		self.globals = Globals::default();
+		self.last_chunk = None;
+		self.imported.clear();
+		self.objects.free();
+	}
+
+	/// The most recently interpreted chunk, `None` until something compiles successfully
+	pub fn last_chunk(&self) -> Option<&Chunk> {
+		self.last_chunk.as_ref()
+	}
+
+	/// Defined global variables with their values
+	pub fn globals(&self) -> impl Iterator<Item = (&str, Value)> {
+		self.globals
+			.iter()
+			.map(|(name, value)| (name.as_str(), value))
+	}
+
+	/// Copies the defined globals out of the VM, see [Snapshot]
+	pub fn snapshot(&self) -> Snapshot {
+		self.globals.snapshot()
+	}
+
+	/// Defines the globals saved in `snapshot`, e.g. after [Vm::reset_all] or in a new VM
+	pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), object::AllocationLimitExceeded> {
+		self.globals.restore(snapshot, &mut self.objects)
+	}
+
+	/// Runs the script at `path`. `import` statements in it are resolved relative to the script's
+	/// directory.
+	///
+	/// Imported files run in this VM, so the globals they define are available to the importer
+	/// once the `import` statement has executed. Every file runs at most once, importing it again
+	/// (including cyclic imports) does nothing.
+	pub fn interpret_file(&mut self, path: impl AsRef<Path>) -> Result<Value, InterpretError> {
+		let path = path.as_ref().canonicalize()?;
+		let source = std::fs::read_to_string(&path)?;
+		self.imported.insert(path.clone());
+
+		let parent = path.parent().map(Path::to_path_buf);
+		let previous_base_path = std::mem::replace(&mut self.base_path, parent);
+		let result = self.interpret(&source);
+		self.base_path = previous_base_path;
+		result
+	}
+
+	fn import(&mut self, path: &str) -> Result<(), InterpretError> {
+		let path = match &self.base_path {
+			Some(base_path) => base_path.join(path),
+			None => PathBuf::from(path),
+		};
+		if self.imported.contains(&path.canonicalize()?) {
+			return Ok(());
+		}
+		self.interpret_file(path)?;
+		Ok(())
 	}
 
 	pub fn run(&mut self, chunk: &mut Chunk) -> Result<Value, InterpretError> {
-		let chunk_iter = chunk.iter().with_offset();
-
-		for instruction in chunk_iter {
-			let (instruction, offset) = instruction?;
-
-			if self.debug {
-				println!("{:?}", self.stack);
-				let mut s = String::new();
-				chunk
-					.disassemble_instruction_to_write(offset, &instruction, &mut s)
-					.unwrap();
-				println!("{s}");
+		if self.dispatch == Dispatch::Table {
+			return self.run_table(chunk);
+		}
+
+		let mut ip = 0;
+		// Local variable slots are relative to the stack at the start of the chunk
+		let base = self.stack.len();
+		let mut handlers: Vec<Handler> = Vec::new();
+
+		while let Some(instruction) = chunk.decode_instruction(ip) {
+			let instruction = instruction?;
+			let offset = ip;
+			ip += instruction.byte_len();
+
+			if self.debug || self.trace.is_some() {
+				self.trace_instruction(chunk, offset, &instruction)?;
+			}
+
+			if self.stack.len() + MAX_PUSHES > STACK_MAX {
+				return Err(runtime_error(chunk, offset, RuntimeError::StackOverflow));
 			}
 
 			match (instruction.opcode, instruction.kind) {
 				(OpCode::Return, _) => {
-					let val = self.stack.pop().unwrap_or_default();
-					return Ok(val);
+					if self.stack.len() > base {
+						return Ok(self.stack.pop().unwrap_or_default());
+					}
+					return Ok(Value::Nil);
 				}
 				(OpCode::Nil, _) => {
 					self.stack.push(Value::Nil);
@@ -123,8 +593,8 @@ impl<W: Write> Vm<W> {
 					self.stack.push(Value::Bool(true));
 				}
 				(OpCode::Equal, _) => {
-					let value_b = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
-					let value_a = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
+					let value_b = self.pop(chunk, offset)?;
+					let value_a = self.pop(chunk, offset)?;
 					self.stack.push(Value::Bool(value_a == value_b));
 				}
 				(OpCode::Greater, _) => {
@@ -153,46 +623,7 @@ impl<W: Write> Vm<W> {
 					)?;
 					self.stack.push(Value::Bool(value_a < value_b));
 				}
-				(OpCode::Add, _) => {
-					let value_b = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
-					let value_a = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
-					match (&value_a, &value_b) {
-						(Value::Number(a), Value::Number(b)) => {
-							self.stack.push(Value::Number(a + b))
-						}
-						(Value::Object(a), Value::Object(b)) => unsafe {
-							let (a, b): (&Object, &Object) = (&**a, &**b);
-							match (&a.kind, &b.kind) {
-								(ObjectKind::String, ObjectKind::String) => {
-									let str_a = a.as_obj_string().unwrap();
-									let str_b = b.as_obj_string().unwrap();
-									let object =
-										self.objects.take_string(format!("{str_a}{str_b}"));
-									self.stack.push(Value::Object(object));
-								}
-								_ => {
-									return Err(InterpretError::Runtime {
-										source: RuntimeError::InvalidTypes(InvalidTypesError {
-											kind:
-												InvalidTypeErrorKind::ExpectedNumberOrStringOperand,
-											values: vec![value_a, value_b],
-										}),
-										line: *chunk.lines.get(offset).expect("fix your lines"),
-									})
-								}
-							}
-						},
-						_ => {
-							return Err(InterpretError::Runtime {
-								source: RuntimeError::InvalidTypes(InvalidTypesError {
-									kind: InvalidTypeErrorKind::ExpectedNumberOrStringOperand,
-									values: vec![value_a, value_b],
-								}),
-								line: *chunk.lines.get(offset).expect("fix your lines"),
-							})
-						}
-					}
-				}
+				(OpCode::Add, _) => self.add(chunk, offset)?,
 				(OpCode::Subtract, _) => {
 					let value_b = self.pop_number(
 						InvalidTypeErrorKind::ExpectedNumberOperand,
@@ -232,8 +663,64 @@ impl<W: Write> Vm<W> {
 					)?;
 					self.stack.push(Value::Number(value_a / value_b));
 				}
+				(OpCode::FloorDivide, _) => {
+					let value_b = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					let value_a = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					self.stack.push(Value::Number((value_a / value_b).floor()));
+				}
+				// Truncated remainder, takes the sign of the dividend: -7 % 3 == -1
+				(OpCode::Modulo, _) => {
+					let value_b = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					let value_a = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					self.stack.push(Value::Number(value_a % value_b));
+				}
+				// Floored modulo, takes the sign of the divisor: -7 mod 3 == 2
+				(OpCode::FloorModulo, _) => {
+					let value_b = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					let value_a = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					self.stack.push(Value::Number(
+						value_a - value_b * (value_a / value_b).floor(),
+					));
+				}
+				(OpCode::Power, _) => {
+					let value_b = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					let value_a = self.pop_number(
+						InvalidTypeErrorKind::ExpectedNumberOperand,
+						chunk,
+						offset,
+					)?;
+					self.stack.push(Value::Number(value_a.powf(value_b)));
+				}
 				(OpCode::Not, _) => {
-					let value = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
+					let value = self.pop(chunk, offset)?;
 					self.stack.push(Value::Bool(value.is_falsey()));
 				}
 				(OpCode::Negate, _) => {
@@ -245,33 +732,136 @@ impl<W: Write> Vm<W> {
 					self.stack.push(Value::Number(-value));
 				}
 				(OpCode::Print, _) => {
-					let value = self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
-					self.stdout.write_fmt(format_args!("{value}")).unwrap();
+					let value = self.pop(chunk, offset)?;
+					self.print(value)?;
+				}
+				(OpCode::PrintSep, _) => {
+					self.stdout.write_all(b" ")?;
 				}
 				(OpCode::Pop, _) => {
-					self.stack.pop().ok_or(InterpretError::GenericRuntime)?;
+					self.pop(chunk, offset)?;
+				}
+				(OpCode::PopN, InstructionKind::Byte { operand }) => {
+					let len = self
+						.stack
+						.len()
+						.checked_sub(operand as usize)
+						.ok_or_else(|| {
+							runtime_error(chunk, offset, RuntimeError::StackUnderflow)
+						})?;
+					self.stack.truncate(len);
+				}
+				(OpCode::Dup, _) => {
+					let value = self.peek(chunk, offset)?;
+					self.stack.push(value);
+				}
+				(OpCode::Swap, _) => {
+					let len = self.stack.len();
+					if len < 2 {
+						return Err(runtime_error(chunk, offset, RuntimeError::StackUnderflow));
+					}
+					self.stack.swap(len - 1, len - 2);
 				}
 				(OpCode::Constant, InstructionKind::Constant { v, idx: _idx }) => {
 					self.stack.push(v);
 				}
-				(OpCode::DefineGlobal, InstructionKind::Constant { v, idx: _idx }) => {
-					let name = match v {
-						Value::Object(obj) => obj.cast::<ObjString>(),
-						_ => panic!(),
-					};
-					let value = self.stack.pop().unwrap();
-					self.globals.set(name, value);
+				(OpCode::DefineGlobal, InstructionKind::Short { operand }) => {
+					self.define_global_slot(operand as usize, chunk, offset)?
 				}
-				(OpCode::GetGlobal, InstructionKind::Constant { v, idx: _idx }) => {
-					let name = match v {
-						Value::Object(obj) => obj.cast::<ObjString>(),
-						_ => panic!(),
-					};
-					let value = self.globals.get(name).ok_or(InterpretError::Runtime {
-						source: RuntimeError::UndefinedVariable(unsafe { (*name).to_string() }),
-						line: *chunk.lines.get(offset).expect("fix your lines"),
+				(OpCode::Import, InstructionKind::Constant { v, idx: _idx }) => {
+					self.import_constant(v, chunk, offset)?
+				}
+				(OpCode::GetGlobal, InstructionKind::Short { operand }) => {
+					self.get_global(operand as usize, chunk, offset)?
+				}
+				(OpCode::GetLocal, InstructionKind::Byte { operand }) => {
+					self.get_local(base, operand, chunk, offset)?;
+				}
+				(OpCode::SetLocal, InstructionKind::Byte { operand }) => {
+					self.set_local(base, operand, chunk, offset)?;
+				}
+				(OpCode::SetGlobal, InstructionKind::Short { operand }) => {
+					self.set_global(operand as usize, chunk, offset)?
+				}
+				(OpCode::Jump | OpCode::Loop, InstructionKind::Jump { target }) => {
+					ip = target;
+				}
+				(OpCode::JumpIfFalse, InstructionKind::Jump { target }) => {
+					let value = self.peek(chunk, offset)?;
+					if value.is_falsey() {
+						ip = target;
+					}
+				}
+				(OpCode::JumpIfNotNil, InstructionKind::Jump { target }) => {
+					let value = self.peek(chunk, offset)?;
+					if value != Value::Nil {
+						ip = target;
+					}
+				}
+				(
+					OpCode::LocalLocal,
+					InstructionKind::Fused(Fused::LocalLocal { first, second }),
+				) => {
+					self.get_local(base, first, chunk, offset)?;
+					self.get_local(base, second, chunk, offset)?;
+				}
+				(
+					OpCode::LocalConstant,
+					InstructionKind::Fused(Fused::LocalConstant { slot, v, idx: _idx }),
+				) => {
+					self.get_local(base, slot, chunk, offset)?;
+					self.stack.push(v);
+				}
+				(
+					OpCode::ConstantAdd,
+					InstructionKind::Fused(Fused::ConstantAdd { v, idx: _idx }),
+				) => {
+					self.stack.push(v);
+					self.add(chunk, offset)?;
+				}
+				(OpCode::LocalNegate, InstructionKind::Fused(Fused::LocalNegate { slot })) => {
+					let value = self.local(base, slot, chunk, offset)?;
+					let negated = self.negated(value, chunk, offset)?;
+					self.stack.push(negated);
+				}
+				(OpCode::LocalNot, InstructionKind::Fused(Fused::LocalNot { slot })) => {
+					let value = self.local(base, slot, chunk, offset)?;
+					self.stack.push(Value::Bool(value.is_falsey()));
+				}
+				(
+					OpCode::IncrementLocal,
+					InstructionKind::Fused(Fused::IncrementLocal { slot, v, idx: _idx }),
+				) => self.increment_local(base, slot, v, chunk, offset)?,
+				(OpCode::Call, InstructionKind::Byte { operand }) => {
+					self.call(operand, chunk, offset)?
+				}
+				(OpCode::CheckCount, _) => self.check_count(chunk, offset)?,
+				(OpCode::IsType, InstructionKind::Byte { operand }) => {
+					self.is_type(operand, chunk, offset)?
+				}
+				(OpCode::PushHandler, InstructionKind::Jump { target }) => {
+					handlers.push(Handler {
+						catch_ip: target,
+						stack_len: self.stack.len(),
+					});
+				}
+				(OpCode::PopHandler, _) => {
+					handlers.pop().ok_or_else(|| {
+						runtime_error(
+							chunk,
+							offset,
+							RuntimeError::InvalidBytecode("no exception handler to pop"),
+						)
 					})?;
-					self.stack.push(value.clone());
+				}
+				(OpCode::Throw, _) => {
+					let value = self.pop(chunk, offset)?;
+					let Some(handler) = handlers.pop() else {
+						return Err(runtime_error(chunk, offset, RuntimeError::Uncaught(value)));
+					};
+					self.stack.truncate(handler.stack_len);
+					self.stack.push(value);
+					ip = handler.catch_ip;
 				}
 				(opcode, instruction_kind) => unimplemented!("{opcode:?}, {instruction_kind:?}"),
 			}
@@ -280,24 +870,357 @@ impl<W: Write> Vm<W> {
 		Ok(Value::Nil)
 	}
 
+	fn print(&mut self, value: Value) -> Result<(), InterpretError> {
+		if let Some(hook) = &mut self.print_hook {
+			hook(&value);
+		}
+		self.stdout.write_fmt(format_args!("{value}"))?;
+		Ok(())
+	}
+
+	/// Fails unless the value on top of the stack is a valid `repeat` count
+	fn check_count(&self, chunk: &Chunk, offset: usize) -> Result<(), InterpretError> {
+		let value = self.peek(chunk, offset)?;
+		match value {
+			Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(()),
+			value => Err(runtime_error(
+				chunk,
+				offset,
+				RuntimeError::InvalidType(InvalidTypeError {
+					value,
+					kind: InvalidTypeErrorKind::ExpectedRepeatCount,
+				}),
+			)),
+		}
+	}
+
+	/// Replaces the value on top of the stack with whether it's of the type tagged `tag`
+	fn is_type(&mut self, tag: u8, chunk: &Chunk, offset: usize) -> Result<(), InterpretError> {
+		let ty = *Type::ALL.get(tag as usize).ok_or_else(|| {
+			runtime_error(
+				chunk,
+				offset,
+				RuntimeError::InvalidBytecode("unknown type tag"),
+			)
+		})?;
+		let value = self.pop(chunk, offset)?;
+		// It was just on the stack
+		self.stack
+			.push(Value::Bool(unsafe { value.type_of() } == ty));
+		Ok(())
+	}
+
+	/// Adds two numbers or concatenates two strings. With [Vm::coerce_concat] a string can also be
+	/// concatenated with a number or a bool.
+	fn add(&mut self, chunk: &Chunk, offset: usize) -> Result<(), InterpretError> {
+		let value_b = self.pop(chunk, offset)?;
+		let value_a = self.pop(chunk, offset)?;
+		if let (Value::Number(a), Value::Number(b)) = (&value_a, &value_b) {
+			self.stack.push(Value::Number(a + b));
+			return Ok(());
+		}
+
+		let coercible = |value: &Value| {
+			self.coerce_concat && matches!(value, Value::Number(_) | Value::Bool(_))
+		};
+		// Both were just on the stack
+		let concatenated = match unsafe { (value_a.as_obj_string(), value_b.as_obj_string()) } {
+			(Some(a), Some(b)) => format!("{a}{b}"),
+			(Some(a), None) if coercible(&value_b) => format!("{a}{value_b}"),
+			(None, Some(b)) if coercible(&value_a) => format!("{value_a}{b}"),
+			_ => {
+				return Err(runtime_error(
+					chunk,
+					offset,
+					RuntimeError::InvalidTypes(InvalidTypesError {
+						kind: InvalidTypeErrorKind::ExpectedNumberOrStringOperand,
+						values: vec![value_a, value_b],
+					}),
+				))
+			}
+		};
+		let object = self
+			.objects
+			.take_string(concatenated)
+			.map_err(|err| runtime_error(chunk, offset, err.into()))?;
+		self.stack.push(Value::Object(object));
+		Ok(())
+	}
+
+	fn pop(&mut self, chunk: &Chunk, offset: usize) -> Result<Value, InterpretError> {
+		self.stack
+			.pop()
+			.ok_or_else(|| runtime_error(chunk, offset, RuntimeError::StackUnderflow))
+	}
+
+	fn peek(&self, chunk: &Chunk, offset: usize) -> Result<Value, InterpretError> {
+		self.stack
+			.last()
+			.copied()
+			.ok_or_else(|| runtime_error(chunk, offset, RuntimeError::StackUnderflow))
+	}
+
+	fn get_local(
+		&mut self,
+		base: usize,
+		slot: u8,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		let value = self.local(base, slot, chunk, offset)?;
+		self.stack.push(value);
+		Ok(())
+	}
+
+	/// Value of the local in `slot` of the frame starting at `base`
+	fn local(
+		&self,
+		base: usize,
+		slot: u8,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<Value, InterpretError> {
+		self.stack
+			.get(base + slot as usize)
+			.copied()
+			.ok_or_else(|| runtime_error(chunk, offset, LOCAL_OUT_OF_RANGE))
+	}
+
+	/// Assignment is an expression, the value stays on the stack
+	fn set_local(
+		&mut self,
+		base: usize,
+		slot: u8,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		let value = self.peek(chunk, offset)?;
+		let local = self
+			.stack
+			.get_mut(base + slot as usize)
+			.ok_or_else(|| runtime_error(chunk, offset, LOCAL_OUT_OF_RANGE))?;
+		*local = value;
+		Ok(())
+	}
+
+	fn define_global_slot(
+		&mut self,
+		slot: usize,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		let value = self.pop(chunk, offset)?;
+		if !self.globals.set(slot, value) {
+			return Err(runtime_error(chunk, offset, GLOBAL_OUT_OF_RANGE));
+		}
+		Ok(())
+	}
+
+	/// Only defined globals can be assigned to
+	fn set_global(
+		&mut self,
+		slot: usize,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		if self.globals.get(slot).is_none() {
+			let name = self.global_name(slot, chunk, offset)?;
+			return Err(runtime_error(
+				chunk,
+				offset,
+				RuntimeError::UndefinedVariable(unsafe { (*name).to_string() }),
+			));
+		}
+		let value = self.peek(chunk, offset)?;
+		self.globals.set(slot, value);
+		Ok(())
+	}
+
+	fn get_global(
+		&mut self,
+		slot: usize,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		let value = match self.globals.get(slot) {
+			Some(value) => value,
+			None => {
+				let name = unsafe { &*self.global_name(slot, chunk, offset)? };
+				let supplied = self
+					.missing_global_hook
+					.as_mut()
+					.and_then(|hook| hook(name.as_str()));
+				let value = supplied.ok_or_else(|| {
+					runtime_error(
+						chunk,
+						offset,
+						RuntimeError::UndefinedVariable(name.to_string()),
+					)
+				})?;
+				self.globals.set(slot, value);
+				value
+			}
+		};
+		self.stack.push(value);
+		Ok(())
+	}
+
+	fn global_name(
+		&self,
+		slot: usize,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<*mut ObjString, InterpretError> {
+		self.globals
+			.name(slot)
+			.ok_or_else(|| runtime_error(chunk, offset, GLOBAL_OUT_OF_RANGE))
+	}
+
+	fn call(&mut self, arg_count: u8, chunk: &Chunk, offset: usize) -> Result<(), InterpretError> {
+		let callee_idx = self
+			.stack
+			.len()
+			.checked_sub(arg_count as usize + 1)
+			.ok_or_else(|| runtime_error(chunk, offset, RuntimeError::StackUnderflow))?;
+		let result = self
+			.call_value(callee_idx)
+			.map_err(|source| runtime_error(chunk, offset, source))?;
+		self.stack.truncate(callee_idx);
+		self.stack.push(result);
+		Ok(())
+	}
+
+	/// Runs the import whose path is `path`, a string constant
+	fn import_constant(
+		&mut self,
+		path: Value,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		// Constants of the running chunk stay alive while it runs
+		let path = unsafe { path.as_obj_string() }
+			.ok_or_else(|| {
+				runtime_error(
+					chunk,
+					offset,
+					RuntimeError::InvalidBytecode("import path isn't a string"),
+				)
+			})?
+			.to_string();
+		self.import(&path).map_err(|source| {
+			runtime_error(
+				chunk,
+				offset,
+				RuntimeError::Import {
+					path,
+					source: Box::new(source),
+				},
+			)
+		})
+	}
+
+	/// Calls the value at `callee_idx` with the values above it on the stack as arguments
+	fn call_value(&mut self, callee_idx: usize) -> Result<Value, RuntimeError> {
+		let native = match self.stack[callee_idx] {
+			Value::Object(obj) => unsafe { (*obj).downcast::<ObjNative>() },
+			_ => None,
+		}
+		.ok_or(RuntimeError::NotCallable)?;
+
+		let args = &self.stack[callee_idx + 1..];
+		if let Some(arity) = native.arity {
+			if args.len() != arity {
+				return Err(RuntimeError::Arity {
+					expected: arity,
+					got: args.len(),
+				});
+			}
+		}
+		let mut ctx = native::Context {
+			objects: &mut self.objects,
+			rng: &mut self.rng,
+		};
+		(native.function)(&mut ctx, args)
+	}
+
+	fn trace_instruction(
+		&mut self,
+		chunk: &Chunk,
+		offset: usize,
+		instruction: &Instruction,
+	) -> std::fmt::Result {
+		let mut s = String::from("          ");
+		for value in &self.stack {
+			write!(s, "[ {value} ]")?;
+		}
+		s.push('\n');
+		chunk.disassemble_instruction_to_write(offset, instruction, &mut s)?;
+		s.push('\n');
+
+		if self.debug {
+			print!("{s}");
+		}
+		if let Some(trace) = &mut self.trace {
+			trace.write_str(&s)?;
+		}
+		Ok(())
+	}
+
+	/// `local = local + value` for `IncrementLocal`. Adding a number to a number local updates it
+	/// in place, anything else goes through the same steps as the unfused instructions.
+	fn increment_local(
+		&mut self,
+		base: usize,
+		slot: u8,
+		value: Value,
+		chunk: &Chunk,
+		offset: usize,
+	) -> Result<(), InterpretError> {
+		if let (Some(Value::Number(local)), Value::Number(n)) =
+			(self.stack.get_mut(base + slot as usize), value)
+		{
+			*local += n;
+			let sum = Value::Number(*local);
+			self.stack.push(sum);
+			return Ok(());
+		}
+		self.get_local(base, slot, chunk, offset)?;
+		self.stack.push(value);
+		self.add(chunk, offset)?;
+		self.set_local(base, slot, chunk, offset)
+	}
+
+	/// `-value`, for `LocalNegate` which doesn't have the value on the stack
+	fn negated(&self, value: Value, chunk: &Chunk, offset: usize) -> Result<Value, InterpretError> {
+		match value {
+			Value::Number(n) => Ok(Value::Number(-n)),
+			value => Err(runtime_error(
+				chunk,
+				offset,
+				RuntimeError::InvalidType(InvalidTypeError {
+					value,
+					kind: InvalidTypeErrorKind::ExpectedNumberOperand,
+				}),
+			)),
+		}
+	}
+
 	fn pop_number(
 		&mut self,
 		err_kind: InvalidTypeErrorKind,
 		chunk: &Chunk,
 		offset: usize,
 	) -> Result<f64, InterpretError> {
-		let n: f64 = self
-			.stack
-			.pop()
-			.ok_or(InterpretError::GenericRuntime)?
-			.try_into()
-			.map_err(|val| InterpretError::Runtime {
-				source: RuntimeError::InvalidType(InvalidTypeError {
+		let n: f64 = self.pop(chunk, offset)?.try_into().map_err(|val| {
+			runtime_error(
+				chunk,
+				offset,
+				RuntimeError::InvalidType(InvalidTypeError {
 					value: val,
 					kind: err_kind,
 				}),
-				line: *chunk.lines.get(offset).expect("fix your line vec"),
-			})?;
+			)
+		})?;
 		Ok(n)
 	}
 }
@@ -0,0 +1,566 @@
+//! Alternative interpreter loop selected by [Dispatch::Table](super::Dispatch::Table). Instead of decoding an
+//! [Instruction](crate::chunk::Instruction) and matching on it, the opcode byte indexes a table
+//! of handlers, each of which decodes its own operands and advances `ip`.
+
+use std::io::Write;
+
+use super::{
+	runtime_error, Handler, InterpretError, InvalidTypeErrorKind, RuntimeError, Vm, MAX_PUSHES,
+	STACK_MAX,
+};
+use crate::chunk::{Chunk, DecodeError, OpCode};
+use crate::value::Value;
+
+/// Interpreter state that the match based loop keeps in local variables
+pub(super) struct Registers {
+	/// Offset of the next instruction. Handlers enter with it pointing at their opcode.
+	ip: usize,
+	base: usize,
+	handlers: Vec<Handler>,
+}
+
+pub(super) enum Flow {
+	Continue,
+	Return(Value),
+}
+
+type OpFn<W> = fn(&mut Vm<W>, &Chunk, &mut Registers) -> Result<Flow, InterpretError>;
+
+const OPCODE_COUNT: usize = OpCode::ALL.len();
+
+impl<W: Write> Vm<W> {
+	pub(super) fn run_table(&mut self, chunk: &Chunk) -> Result<Value, InterpretError> {
+		let table: [OpFn<W>; OPCODE_COUNT] = std::array::from_fn(|byte| {
+			handler(OpCode::try_from(byte as u8).expect("opcodes are contiguous"))
+		});
+		let mut regs = Registers {
+			ip: 0,
+			base: self.stack.len(),
+			handlers: Vec::new(),
+		};
+
+		while let Some(&byte) = chunk.code().get(regs.ip) {
+			if self.debug || self.trace.is_some() {
+				if let Some(instruction) = chunk.decode_instruction(regs.ip) {
+					self.trace_instruction(chunk, regs.ip, &instruction?)?;
+				}
+			}
+
+			if self.stack.len() + MAX_PUSHES > STACK_MAX {
+				return Err(runtime_error(chunk, regs.ip, RuntimeError::StackOverflow));
+			}
+
+			let Some(op) = table.get(byte as usize) else {
+				return Err(DecodeError::from(OpCode::try_from(byte).unwrap_err()).into());
+			};
+			if let Flow::Return(value) = op(self, chunk, &mut regs)? {
+				return Ok(value);
+			}
+		}
+
+		Ok(Value::Nil)
+	}
+}
+
+fn handler<W: Write>(opcode: OpCode) -> OpFn<W> {
+	match opcode {
+		OpCode::Constant => constant,
+		OpCode::Nil => |vm, _, regs| push(vm, regs, 1, Value::Nil),
+		OpCode::True => |vm, _, regs| push(vm, regs, 1, Value::Bool(true)),
+		OpCode::False => |vm, _, regs| push(vm, regs, 1, Value::Bool(false)),
+		OpCode::Equal => equal,
+		OpCode::Greater => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Bool(a > b)),
+		OpCode::Less => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Bool(a < b)),
+		OpCode::Add => add,
+		OpCode::Subtract => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number(a - b)),
+		OpCode::Multiply => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number(a * b)),
+		OpCode::Divide => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number(a / b)),
+		OpCode::FloorDivide => {
+			|vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number((a / b).floor()))
+		}
+		OpCode::Modulo => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number(a % b)),
+		OpCode::FloorModulo => |vm, chunk, regs| {
+			binary(vm, chunk, regs, |a, b| {
+				Value::Number(a - b * (a / b).floor())
+			})
+		},
+		OpCode::Power => |vm, chunk, regs| binary(vm, chunk, regs, |a, b| Value::Number(a.powf(b))),
+		OpCode::Not => not,
+		OpCode::Negate => negate,
+		OpCode::Print => print,
+		OpCode::PrintSep => print_sep,
+		OpCode::Pop => pop,
+		OpCode::PopN => pop_n,
+		OpCode::Dup => dup,
+		OpCode::Swap => swap,
+		OpCode::DefineGlobal => define_global,
+		OpCode::GetGlobal => get_global,
+		OpCode::SetGlobal => set_global,
+		OpCode::GetLocal => get_local,
+		OpCode::SetLocal => set_local,
+		OpCode::Jump | OpCode::Loop => jump,
+		OpCode::JumpIfFalse => jump_if_false,
+		OpCode::JumpIfNotNil => jump_if_not_nil,
+		OpCode::PushHandler => push_handler,
+		OpCode::PopHandler => pop_handler,
+		OpCode::Throw => throw,
+		OpCode::Import => import,
+		OpCode::Call => call,
+		OpCode::IsType => is_type,
+		OpCode::CheckCount => check_count,
+		OpCode::LocalLocal => local_local,
+		OpCode::LocalConstant => local_constant,
+		OpCode::ConstantAdd => constant_add,
+		OpCode::LocalNegate => local_negate,
+		OpCode::LocalNot => local_not,
+		OpCode::IncrementLocal => increment_local,
+		OpCode::Return => ret,
+	}
+}
+
+fn invalid_bytecode(chunk: &Chunk, regs: &Registers, what: &'static str) -> InterpretError {
+	runtime_error(chunk, regs.ip, RuntimeError::InvalidBytecode(what))
+}
+
+fn byte_operand(chunk: &Chunk, regs: &Registers) -> Result<u8, InterpretError> {
+	chunk
+		.code()
+		.get(regs.ip + 1)
+		.copied()
+		.ok_or_else(|| invalid_bytecode(chunk, regs, "truncated instruction"))
+}
+
+fn short_operand(chunk: &Chunk, regs: &Registers) -> Result<u16, InterpretError> {
+	match chunk.code().get(regs.ip + 1..regs.ip + 3) {
+		Some(&[hi, lo]) => Ok(u16::from_be_bytes([hi, lo])),
+		_ => Err(invalid_bytecode(chunk, regs, "truncated instruction")),
+	}
+}
+
+/// Absolute target of the jump at `regs.ip`, see [Chunk::decode_instruction]
+fn jump_target(chunk: &Chunk, regs: &Registers) -> Result<usize, InterpretError> {
+	let jump = short_operand(chunk, regs)? as usize;
+	let next = regs.ip + 3;
+	if chunk.code()[regs.ip] == OpCode::Loop as u8 {
+		next.checked_sub(jump)
+			.ok_or_else(|| invalid_bytecode(chunk, regs, "jump before the start of the chunk"))
+	} else {
+		Ok(next + jump)
+	}
+}
+
+fn push<W: Write>(
+	vm: &mut Vm<W>,
+	regs: &mut Registers,
+	len: usize,
+	value: Value,
+) -> Result<Flow, InterpretError> {
+	vm.stack.push(value);
+	regs.ip += len;
+	Ok(Flow::Continue)
+}
+
+fn pop<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.pop(chunk, regs.ip)?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn binary<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+	op: fn(f64, f64) -> Value,
+) -> Result<Flow, InterpretError> {
+	let b = vm.pop_number(InvalidTypeErrorKind::ExpectedNumberOperand, chunk, regs.ip)?;
+	let a = vm.pop_number(InvalidTypeErrorKind::ExpectedNumberOperand, chunk, regs.ip)?;
+	push(vm, regs, 1, op(a, b))
+}
+
+fn constant<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = constant_operand(chunk, regs)?;
+	push(vm, regs, 2, value)
+}
+
+fn equal<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let b = vm.pop(chunk, regs.ip)?;
+	let a = vm.pop(chunk, regs.ip)?;
+	push(vm, regs, 1, Value::Bool(a == b))
+}
+
+fn add<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.add(chunk, regs.ip)?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn not<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.pop(chunk, regs.ip)?;
+	push(vm, regs, 1, Value::Bool(value.is_falsey()))
+}
+
+fn negate<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.pop_number(InvalidTypeErrorKind::ExpectedNumberOperand, chunk, regs.ip)?;
+	push(vm, regs, 1, Value::Number(-value))
+}
+
+fn print<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.pop(chunk, regs.ip)?;
+	vm.print(value)?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn print_sep<W: Write>(
+	vm: &mut Vm<W>,
+	_: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.stdout.write_all(b" ")?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn pop_n<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let count = byte_operand(chunk, regs)?;
+	let len = vm
+		.stack
+		.len()
+		.checked_sub(count as usize)
+		.ok_or_else(|| runtime_error(chunk, regs.ip, RuntimeError::StackUnderflow))?;
+	vm.stack.truncate(len);
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn dup<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.peek(chunk, regs.ip)?;
+	push(vm, regs, 1, value)
+}
+
+fn swap<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let len = vm.stack.len();
+	if len < 2 {
+		return Err(runtime_error(chunk, regs.ip, RuntimeError::StackUnderflow));
+	}
+	vm.stack.swap(len - 1, len - 2);
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn define_global<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = short_operand(chunk, regs)?;
+	vm.define_global_slot(slot as usize, chunk, regs.ip)?;
+	regs.ip += 3;
+	Ok(Flow::Continue)
+}
+
+fn get_global<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = short_operand(chunk, regs)?;
+	vm.get_global(slot as usize, chunk, regs.ip)?;
+	regs.ip += 3;
+	Ok(Flow::Continue)
+}
+
+fn get_local<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = byte_operand(chunk, regs)?;
+	vm.get_local(regs.base, slot, chunk, regs.ip)?;
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn set_local<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = byte_operand(chunk, regs)?;
+	vm.set_local(regs.base, slot, chunk, regs.ip)?;
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn set_global<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = short_operand(chunk, regs)?;
+	vm.set_global(slot as usize, chunk, regs.ip)?;
+	regs.ip += 3;
+	Ok(Flow::Continue)
+}
+
+/// Operand of the second instruction in a superinstruction, which follows the first one's operand
+/// and the second one's original opcode
+fn second_operand(chunk: &Chunk, regs: &Registers) -> Result<u8, InterpretError> {
+	chunk
+		.code()
+		.get(regs.ip + 3)
+		.copied()
+		.ok_or_else(|| invalid_bytecode(chunk, regs, "truncated instruction"))
+}
+
+fn local_local<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.get_local(regs.base, byte_operand(chunk, regs)?, chunk, regs.ip)?;
+	vm.get_local(regs.base, second_operand(chunk, regs)?, chunk, regs.ip)?;
+	regs.ip += 4;
+	Ok(Flow::Continue)
+}
+
+fn local_constant<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.get_local(regs.base, byte_operand(chunk, regs)?, chunk, regs.ip)?;
+	let value = second_constant_operand(chunk, regs)?;
+	push(vm, regs, 4, value)
+}
+
+fn constant_add<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = constant_operand(chunk, regs)?;
+	vm.stack.push(value);
+	vm.add(chunk, regs.ip)?;
+	regs.ip += 3;
+	Ok(Flow::Continue)
+}
+
+fn local_negate<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.local(regs.base, byte_operand(chunk, regs)?, chunk, regs.ip)?;
+	let negated = vm.negated(value, chunk, regs.ip)?;
+	push(vm, regs, 3, negated)
+}
+
+fn local_not<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.local(regs.base, byte_operand(chunk, regs)?, chunk, regs.ip)?;
+	push(vm, regs, 3, Value::Bool(value.is_falsey()))
+}
+
+fn increment_local<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let slot = byte_operand(chunk, regs)?;
+	let value = second_constant_operand(chunk, regs)?;
+	vm.increment_local(regs.base, slot, value, chunk, regs.ip)?;
+	regs.ip += 7;
+	Ok(Flow::Continue)
+}
+
+/// The constant the operand of a `Constant` or `Import` instruction, fused or not, refers to
+fn constant_operand(chunk: &Chunk, regs: &Registers) -> Result<Value, InterpretError> {
+	constant_at(chunk, regs, byte_operand(chunk, regs)?)
+}
+
+/// The constant of `LocalConstant` and `IncrementLocal`, whose second instruction is a
+/// `Constant`
+fn second_constant_operand(chunk: &Chunk, regs: &Registers) -> Result<Value, InterpretError> {
+	constant_at(chunk, regs, second_operand(chunk, regs)?)
+}
+
+/// Constant `idx` of the chunk. A missing one is the same decode error [Dispatch::Match] reports.
+///
+/// [Dispatch::Match]: super::Dispatch::Match
+fn constant_at(chunk: &Chunk, regs: &Registers, idx: u8) -> Result<Value, InterpretError> {
+	chunk
+		.constant(idx as usize)
+		.ok_or(InterpretError::Decode(DecodeError::MalformedConstant {
+			offset: regs.ip,
+			idx: idx as usize,
+		}))
+}
+
+fn jump<W: Write>(
+	_: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	regs.ip = jump_target(chunk, regs)?;
+	Ok(Flow::Continue)
+}
+
+fn jump_if_false<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.peek(chunk, regs.ip)?;
+	regs.ip = match value.is_falsey() {
+		true => jump_target(chunk, regs)?,
+		false => regs.ip + 3,
+	};
+	Ok(Flow::Continue)
+}
+
+fn jump_if_not_nil<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.peek(chunk, regs.ip)?;
+	regs.ip = match value != Value::Nil {
+		true => jump_target(chunk, regs)?,
+		false => regs.ip + 3,
+	};
+	Ok(Flow::Continue)
+}
+
+fn push_handler<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	regs.handlers.push(Handler {
+		catch_ip: jump_target(chunk, regs)?,
+		stack_len: vm.stack.len(),
+	});
+	regs.ip += 3;
+	Ok(Flow::Continue)
+}
+
+fn pop_handler<W: Write>(
+	_: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	regs.handlers
+		.pop()
+		.ok_or_else(|| invalid_bytecode(chunk, regs, "no exception handler to pop"))?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn throw<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let value = vm.pop(chunk, regs.ip)?;
+	let Some(handler) = regs.handlers.pop() else {
+		return Err(runtime_error(chunk, regs.ip, RuntimeError::Uncaught(value)));
+	};
+	vm.stack.truncate(handler.stack_len);
+	vm.stack.push(value);
+	regs.ip = handler.catch_ip;
+	Ok(Flow::Continue)
+}
+
+fn import<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let path = constant_operand(chunk, regs)?;
+	vm.import_constant(path, chunk, regs.ip)?;
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn call<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let arg_count = byte_operand(chunk, regs)?;
+	vm.call(arg_count, chunk, regs.ip)?;
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn check_count<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	vm.check_count(chunk, regs.ip)?;
+	regs.ip += 1;
+	Ok(Flow::Continue)
+}
+
+fn is_type<W: Write>(
+	vm: &mut Vm<W>,
+	chunk: &Chunk,
+	regs: &mut Registers,
+) -> Result<Flow, InterpretError> {
+	let tag = byte_operand(chunk, regs)?;
+	vm.is_type(tag, chunk, regs.ip)?;
+	regs.ip += 2;
+	Ok(Flow::Continue)
+}
+
+fn ret<W: Write>(vm: &mut Vm<W>, _: &Chunk, regs: &mut Registers) -> Result<Flow, InterpretError> {
+	if vm.stack.len() > regs.base {
+		return Ok(Flow::Return(vm.stack.pop().unwrap_or_default()));
+	}
+	Ok(Flow::Return(Value::Nil))
+}
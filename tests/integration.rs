@@ -1,4 +1,15 @@
-use lox_v2::vm::Vm;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lox_v2::chunk::{Chunk, DecodeError, InstructionKind, OpCode};
+use lox_v2::compiler;
+use lox_v2::globals::{Globals, SnapshotValue};
+use lox_v2::native;
+use lox_v2::object::{Allocator, ObjNative, ObjString};
+use lox_v2::scanner::{Scanner, TokenKind};
+use lox_v2::value::{Type, Value};
+use lox_v2::vm::{Dispatch, InterpretError, RuntimeError, Vm};
 
 fn run_and_capture_stdout(source: &str) -> String {
 	let mut stdout = Vec::new();
@@ -55,6 +66,18 @@ fn falsey_comparisons() {
 	assert_eq!(stdout, "true");
 }
 
+#[test]
+fn truthiness() {
+	assert_eq!(
+		run_and_capture_stdout(
+			r#"print !0, !"", !nil, !false, !true; while (0) { print "ran"; break; }"#
+		),
+		"false false true true falseran"
+	);
+	assert!(Value::Number(0.0).is_truthy());
+	assert!(Value::Nil.is_falsey());
+}
+
 #[test]
 fn print_statement() {
 	let stdout = run_and_capture_stdout(
@@ -66,3 +89,2144 @@ fn print_statement() {
 	);
 	assert_eq!(stdout, "beignets with cafe au lait");
 }
+
+#[test]
+fn nil_coalescing() {
+	let stdout = run_and_capture_stdout("print nil ?? 5;");
+	assert_eq!(stdout, "5");
+
+	let stdout = run_and_capture_stdout("print 3 ?? (1/0);");
+	assert_eq!(stdout, "3");
+
+	// The right side would fail with an undefined variable error if it was evaluated
+	let stdout = run_and_capture_stdout("print false ?? undefined;");
+	assert_eq!(stdout, "false");
+
+	let stdout = run_and_capture_stdout("print nil ?? nil ?? 1 + 2;");
+	assert_eq!(stdout, "3");
+}
+
+#[test]
+fn dup_and_swap() {
+	let mut chunk = Chunk::default();
+	let one = chunk.write_constant(Value::Number(1.0)) as u8;
+	let two = chunk.write_constant(Value::Number(2.0)) as u8;
+	chunk.write(OpCode::Constant, 1);
+	chunk.write(one, 1);
+	chunk.write(OpCode::Constant, 1);
+	chunk.write(two, 1);
+	chunk.write(OpCode::Swap, 1);
+	chunk.write(OpCode::Dup, 1);
+	chunk.write(OpCode::Print, 1);
+	chunk.write(OpCode::Print, 1);
+	chunk.write(OpCode::Print, 1);
+	chunk.write(OpCode::Return, 1);
+
+	let disassembly = chunk.disassemble("dup_and_swap");
+	assert!(disassembly.contains("0004    | OP_SWAP"), "{disassembly}");
+	assert!(disassembly.contains("0005    | OP_DUP"), "{disassembly}");
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.run(&mut chunk).unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "112");
+}
+
+#[test]
+fn block_pops_all_locals_at_once() {
+	let mut chunk = Chunk::default();
+	let mut objects = Allocator::default();
+	compiler::compile(
+		"{ var a = 1; var b = 2; var c = 3; }",
+		&mut chunk,
+		false,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+
+	let pops: Vec<_> = chunk
+		.iter()
+		.map(Result::unwrap)
+		.filter(|instruction| matches!(instruction.opcode, OpCode::Pop | OpCode::PopN))
+		.collect();
+	assert_eq!(pops.len(), 1);
+	assert!(matches!(pops[0].opcode, OpCode::PopN));
+	assert!(matches!(pops[0].kind, InstructionKind::Byte { operand: 3 }));
+}
+
+#[test]
+fn full_block_pops_every_local() {
+	// As many locals as a chunk can address, more than one `PopN` pops
+	let locals: String = (0..256)
+		.map(|i| format!("var v{i} = {};", i % 2 == 0))
+		.collect();
+	assert_eq!(
+		run_and_capture_stdout(&format!(
+			"var r = 1; {{ {locals} r = v254; }} print r; while (true) {{ {locals} r = v255; break; }} print r;"
+		)),
+		"truefalse"
+	);
+}
+
+#[test]
+fn trailing_pops_are_merged() {
+	let pops = |merge_pops| {
+		let mut chunk = Chunk::default();
+		compiler::compile_with_options(
+			"{ var a = 1; var b = 2; a; b = a; a + b; }",
+			&mut chunk,
+			false,
+			&mut Allocator::default(),
+			&mut Globals::default(),
+			compiler::Options {
+				merge_pops,
+				..compiler::Options::default()
+			},
+		)
+		.unwrap();
+
+		let pops: Vec<_> = chunk
+			.iter()
+			.map(Result::unwrap)
+			.filter(|instruction| matches!(instruction.opcode, OpCode::Pop | OpCode::PopN))
+			.map(|instruction| match instruction.kind {
+				InstructionKind::Byte { operand } => operand,
+				_ => 1,
+			})
+			.collect();
+		(pops, chunk.iter().count())
+	};
+	// The pop of `a + b` and the pops of both locals are a single instruction
+	assert_eq!(pops(true), (vec![1, 1, 3], 12));
+	assert_eq!(pops(false), (vec![1, 1, 1, 2], 13));
+
+	// Pops that a jump lands between stay apart
+	let stdout = run_and_capture_stdout(
+		"var r; { var a = 1; while (true) { a = a + 1; break; } r = a; } print r;",
+	);
+	assert_eq!(stdout, "2");
+}
+
+#[test]
+fn local_variables() {
+	let stdout = run_and_capture_stdout(
+		r#"
+	var a = "global";
+	{
+		var a = "outer";
+		{
+			var a = "inner";
+			print a;
+		}
+		print a;
+	}
+	print a;
+	"#,
+	);
+	assert_eq!(stdout, "innerouterglobal");
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(vm.interpret("{ var a = a; }").is_err());
+}
+
+#[derive(Clone, Default)]
+struct SharedTrace(Rc<RefCell<String>>);
+
+impl std::fmt::Write for SharedTrace {
+	fn write_str(&mut self, s: &str) -> std::fmt::Result {
+		self.0.borrow_mut().push_str(s);
+		Ok(())
+	}
+}
+
+#[test]
+fn deterministic_trace() {
+	let trace = SharedTrace::default();
+	let mut vm = Vm::new(Vec::new());
+	vm.trace_to(trace.clone());
+	vm.interpret("var drink = \"tea\";\n{ var n = 1; print drink ?? n; }")
+		.unwrap();
+
+	let expected = [
+		"          ",
+		"0000    1 OP_CONSTANT           0 'tea'",
+		"          [ tea ]",
+		"0002    | OP_DEFINE_GLOBAL      0",
+		"          ",
+		"0005    2 OP_CONSTANT           1 '1'",
+		"          [ 1 ]",
+		"0007    | OP_GET_GLOBAL         0",
+		"          [ 1 ][ tea ]",
+		"0010    | OP_JUMP_IF_NOT_NIL -> 16",
+		"          [ 1 ][ tea ]",
+		"0016    | OP_PRINT           ",
+		"          [ 1 ]",
+		"0017    | OP_POP             ",
+		"          ",
+		"0018    | OP_RETURN          ",
+		"",
+	]
+	.join("\n");
+	assert_eq!(*trace.0.borrow(), expected);
+}
+
+#[test]
+fn concatenated_strings_are_interned() {
+	let stdout = run_and_capture_stdout(r#"print ("a" + "b") == ("a" + "b");"#);
+	assert_eq!(stdout, "true");
+
+	let stdout = run_and_capture_stdout(r#"print ("a" + "b") == "ab";"#);
+	assert_eq!(stdout, "true");
+
+	let stdout = run_and_capture_stdout(r#"print ("a" + "b") == "ba";"#);
+	assert_eq!(stdout, "false");
+}
+
+#[test]
+fn memory_stats() {
+	let mut objects = Allocator::default();
+	assert_eq!(objects.object_count(), 0);
+	assert_eq!(objects.bytes_allocated(), 0);
+
+	objects.copy_string("ala").unwrap();
+	objects.take_string("ma kota".to_string()).unwrap();
+	assert_eq!(objects.object_count(), 2);
+	let bytes_allocated = objects.bytes_allocated();
+	assert!(bytes_allocated >= "ala".len() + "ma kota".len());
+
+	// Interned strings don't allocate again
+	objects.copy_string("ala").unwrap();
+	assert_eq!(objects.object_count(), 2);
+	assert_eq!(objects.bytes_allocated(), bytes_allocated);
+
+	let mut vm = Vm::new(Vec::new());
+	vm.interpret(r#"var a = "x"; var b = a + "y";"#).unwrap();
+	let stats = vm.memory_stats();
+	// "x", "a", "b", "y" and "xy"
+	assert_eq!(stats.object_count, 5);
+	assert_eq!(stats.globals_count, 2);
+	assert!(stats.globals_bytes > 0);
+}
+
+#[test]
+fn max_objects() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	// The compiled program holds 7 strings: "a", "x", and "1" through "5"
+	vm.set_max_objects(Some(9));
+	let result = vm.interpret(
+		r#"
+	var a = "x";
+	print a + "1";
+	print a + "2";
+	print a + "3";
+	print a + "4";
+	print a + "5";
+	"#,
+	);
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::AllocationLimitExceeded(_),
+				line: 5,
+			})
+		),
+		"{result:?}"
+	);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "x1x2");
+
+	let mut vm = Vm::new(Vec::new());
+	vm.set_max_objects(Some(1));
+	let result = vm.interpret(r#"print "a" + "b";"#);
+	assert!(matches!(
+		result,
+		Err(InterpretError::Compile {
+			source: compiler::Error::AllocationLimitExceeded(_),
+			..
+		})
+	));
+}
+
+#[test]
+fn modulo() {
+	// `%` is the truncated remainder, the result has the sign of the dividend
+	assert_eq!(run_and_capture_stdout("print -7 % 3;"), "-1");
+	assert_eq!(run_and_capture_stdout("print 7 % -3;"), "1");
+	assert_eq!(run_and_capture_stdout("print 7.5 % 2;"), "1.5");
+
+	// `mod` is the floored modulo, the result has the sign of the divisor
+	assert_eq!(run_and_capture_stdout("print -7 mod 3;"), "2");
+	assert_eq!(run_and_capture_stdout("print 7 mod -3;"), "-2");
+	assert_eq!(run_and_capture_stdout("print 7 mod 3;"), "1");
+
+	assert_eq!(run_and_capture_stdout("print 1 + 7 % 4 * 2;"), "7");
+}
+
+#[test]
+fn exponentiation() {
+	assert_eq!(run_and_capture_stdout("print 2 ** 10;"), "1024");
+	assert_eq!(run_and_capture_stdout("print 2 ** 3 ** 2;"), "512");
+	assert_eq!(run_and_capture_stdout("print (2 ** 3) ** 2;"), "64");
+	assert_eq!(run_and_capture_stdout("print -2 ** 2;"), "-4");
+	assert_eq!(run_and_capture_stdout("print 2 ** -1;"), "0.5");
+	assert_eq!(run_and_capture_stdout("print 3 * 2 ** 2;"), "12");
+
+	let mut vm = Vm::new(Vec::new());
+	let result = vm.interpret(r#"print "a" ** 2;"#);
+	assert!(matches!(
+		result,
+		Err(InterpretError::Runtime {
+			source: RuntimeError::InvalidType(_),
+			..
+		})
+	));
+}
+
+#[test]
+fn floor_division() {
+	assert_eq!(run_and_capture_stdout("print 7 div 2;"), "3");
+	assert_eq!(run_and_capture_stdout("print -7 div 2;"), "-4");
+	assert_eq!(run_and_capture_stdout("print 7 / 2;"), "3.5");
+	assert_eq!(run_and_capture_stdout("print 1 div 0;"), "inf");
+
+	// `//` still starts a comment
+	assert_eq!(run_and_capture_stdout("print 7 // 2;\n;"), "7");
+}
+
+#[test]
+fn match_statement() {
+	let source = r#"
+	match value {
+		1 => print "one";
+		-2 => print "minus two";
+		"x" => print "x";
+		nil => print "nil";
+		_ => { var other = "other"; print other; }
+	}
+	"#;
+
+	for (value, expected) in [
+		("1", "one"),
+		("-2", "minus two"),
+		(r#""x""#, "x"),
+		("nil", "nil"),
+		("false", "other"),
+		("3", "other"),
+	] {
+		let stdout = run_and_capture_stdout(&format!("var value = {value};{source}"));
+		assert_eq!(stdout, expected);
+	}
+
+	// Nothing is left on the stack once the match is over
+	let stdout = run_and_capture_stdout(
+		r#"{ var a = "a"; match 1 { 1 => print a; _ => print "?"; } var b = "b"; print b; }"#,
+	);
+	assert_eq!(stdout, "ab");
+
+	let mut vm = Vm::new(Vec::new());
+	let result = vm.interpret("match 1 { 1 => print 1; }");
+	assert!(matches!(
+		result,
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedWildcardArm,
+			..
+		})
+	));
+}
+
+#[test]
+fn try_catch() {
+	let stdout = run_and_capture_stdout(
+		r#"
+	try {
+		var a = "before";
+		print a;
+		throw "oops";
+		print "after";
+	} catch (e) {
+		print e;
+	}
+	"#,
+	);
+	assert_eq!(stdout, "beforeoops");
+
+	// Rethrowing from a catch block reaches the outer handler, and the stack is unwound so
+	// locals declared before the try block are still in their slots
+	let stdout = run_and_capture_stdout(
+		r#"
+	{
+		var outer = "outer";
+		try {
+			try {
+				var inner = 1;
+				throw 1 + 1;
+			} catch (e) {
+				throw e * 10;
+			}
+		} catch (e) {
+			print e;
+			print outer;
+		}
+		print outer;
+	}
+	"#,
+	);
+	assert_eq!(stdout, "20outerouter");
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	let result = vm.interpret(
+		r#"
+	try { print 1; } catch (e) { print e; }
+	throw "escaped";
+	"#,
+	);
+	match result {
+		Err(InterpretError::Runtime {
+			source: RuntimeError::Uncaught(value),
+			line: 3,
+		}) => assert_eq!(value.to_string(), "escaped"),
+		_ => panic!("unexpected result {result:?}"),
+	}
+	assert_eq!(String::from_utf8(stdout).unwrap(), "1");
+}
+
+/// Creates an empty directory for a test's files
+fn test_dir(name: &str) -> std::path::PathBuf {
+	let dir = std::env::temp_dir().join(format!("lox-v2-{}-{name}", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+#[test]
+fn import() {
+	let dir = test_dir("import");
+	std::fs::create_dir(dir.join("lib")).unwrap();
+	std::fs::write(
+		dir.join("main.lox"),
+		r#"import "lib/greeting.lox"; print greeting; import "lib/greeting.lox";"#,
+	)
+	.unwrap();
+	std::fs::write(
+		dir.join("lib/greeting.lox"),
+		r#"import "name.lox"; var greeting = "hello " + name; print "loaded ";"#,
+	)
+	.unwrap();
+	// Imports are relative to the importing file and cycles are only run once
+	std::fs::write(
+		dir.join("lib/name.lox"),
+		r#"import "greeting.lox"; import "../main.lox"; var name = "lox";"#,
+	)
+	.unwrap();
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.interpret_file(dir.join("main.lox")).unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "loaded hello lox");
+
+	// Starting from name.lox, greeting.lox runs before `name` is defined
+	let mut vm = Vm::new(Vec::new());
+	let result = vm.interpret_file(dir.join("lib/name.lox"));
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::Import { .. },
+				..
+			})
+		),
+		"{result:?}"
+	);
+
+	std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn prelude() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_prelude();
+	vm.load_prelude(r#"var greeting = "hello";"#).unwrap();
+	vm.interpret("print greeting; print PI;").unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "hello3.14159");
+}
+
+#[test]
+fn io_natives() {
+	let dir = test_dir("io_natives");
+	let path = dir.join("out.txt");
+	let path = path.to_str().unwrap();
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_io_natives();
+	vm.interpret(&format!(
+		r#"writeFile("{path}", "hello " + "file"); print readFile("{path}");"#
+	))
+	.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "hello file");
+
+	let mut vm = Vm::new(Vec::new()).with_io_natives();
+	let missing = dir.join("missing.txt");
+	let result = vm.interpret(&format!(r#"readFile("{}");"#, missing.display()));
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::Native(_),
+				..
+			})
+		),
+		"{result:?}"
+	);
+	let result = vm.interpret(r#"readFile("a", "b");"#);
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::Arity {
+					expected: 1,
+					got: 2
+				},
+				..
+			})
+		),
+		"{result:?}"
+	);
+	let result = vm.interpret(r#""readFile"();"#);
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::NotCallable,
+				..
+			})
+		),
+		"{result:?}"
+	);
+
+	// Not available unless opted in
+	let mut vm = Vm::new(Vec::new());
+	assert!(vm.interpret(r#"readFile("a");"#).is_err());
+
+	std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn getenv() {
+	std::env::set_var("LOX_V2_TEST_GETENV", "from env");
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_io_natives();
+	vm.interpret(r#"print getenv("LOX_V2_TEST_GETENV"); print getenv("LOX_V2_TEST_UNSET");"#)
+		.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "from envnil");
+}
+
+#[test]
+fn loops() {
+	// The body of a do-while runs before the condition is checked
+	assert_eq!(
+		run_and_capture_stdout(r#"do print "once"; while (false); print "after";"#),
+		"onceafter"
+	);
+	assert_eq!(
+		run_and_capture_stdout(r#"while (false) print "never"; print "after";"#),
+		"after"
+	);
+	assert_eq!(
+		run_and_capture_stdout(r#"do { print 1; continue; print 2; } while (false); print 3;"#),
+		"13"
+	);
+	assert_eq!(
+		run_and_capture_stdout(r#"while (true) { print 1; break; print 2; } print 3;"#),
+		"13"
+	);
+
+	// Breaking out of nested blocks pops their locals, so `after` gets the right slot
+	assert_eq!(
+		run_and_capture_stdout(
+			r#"{ do { var a = "a"; { var b = "b"; break; } } while (true); var after = "ok"; print after; }"#
+		),
+		"ok"
+	);
+
+	// Breaking out of a try block removes its handler
+	let mut vm = Vm::new(Vec::new());
+	let result =
+		vm.interpret(r#"while (true) { try { break; } catch (e) { print e; } } throw "out";"#);
+	assert!(
+		matches!(
+			result,
+			Err(InterpretError::Runtime {
+				source: RuntimeError::Uncaught(_),
+				..
+			})
+		),
+		"{result:?}"
+	);
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret("break;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::OutsideLoop { keyword: "break" },
+			..
+		})
+	));
+}
+
+#[test]
+fn seeded_random() {
+	let source = "print random(); print randomInt(1, 6); print random(); print randomInt(-3, 3);";
+	let run = |seed| {
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout).with_std_natives();
+		vm.seed_rng(seed);
+		vm.interpret(source).unwrap();
+		String::from_utf8(stdout).unwrap()
+	};
+	assert_eq!(run(42), run(42));
+	assert_ne!(run(42), run(43));
+
+	let mut vm = Vm::new(Vec::new()).with_std_natives();
+	assert!(vm.interpret("print randomInt(5, 5);").is_ok());
+	assert!(vm.interpret("randomInt(2, 1);").is_err());
+	assert!(vm.interpret("randomInt(0, 1.5);").is_err());
+}
+
+#[test]
+fn math_natives() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret("print max(1, 5, 3); print min(4, -2, 8); print abs(-4); print max(7);")
+		.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "5-247");
+
+	let mut vm = Vm::new(Vec::new()).with_std_natives();
+	assert!(matches!(
+		vm.interpret("max();"),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::Native(_),
+			..
+		})
+	));
+	assert!(matches!(
+		vm.interpret(r#"min(1, "2");"#),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::InvalidType(_),
+			..
+		})
+	));
+}
+
+#[test]
+fn format_native() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret(r#"print format("{} + {} = {}", 1, 2, 3); print format("{{{}}}", "x");"#)
+		.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "1 + 2 = 3{x}");
+
+	let mut vm = Vm::new(Vec::new()).with_std_natives();
+	for source in [
+		r#"format("{} {}", 1);"#,
+		r#"format("{}", 1, 2);"#,
+		r#"format("{");"#,
+		"format();",
+	] {
+		assert!(
+			matches!(
+				vm.interpret(source),
+				Err(InterpretError::Runtime {
+					source: RuntimeError::Native(_),
+					..
+				})
+			),
+			"{source}"
+		);
+	}
+}
+
+#[test]
+fn ord_chr() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret(r#"print chr(65); print ord("A"); print ord("ążę"); print chr(ord("ż")) == "ż";"#)
+		.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "A65261true");
+
+	let mut vm = Vm::new(Vec::new()).with_std_natives();
+	for source in [r#"ord("");"#, "chr(-1);", "chr(55296);", "chr(1114112);"] {
+		assert!(
+			matches!(
+				vm.interpret(source),
+				Err(InterpretError::Runtime {
+					source: RuntimeError::Native(_),
+					..
+				})
+			),
+			"{source}"
+		);
+	}
+}
+
+#[test]
+fn coerce_concat() {
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret(r#"print "n=" + 5;"#),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::InvalidTypes(_),
+			..
+		})
+	));
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.coerce_concat = true;
+	vm.interpret(r#"print "n=" + 5; print true + "!"; print "x" + "y"; print 1 + 2;"#)
+		.unwrap();
+	assert!(matches!(
+		vm.interpret(r#"print "n=" + nil;"#),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::InvalidTypes(_),
+			..
+		})
+	));
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "n=5true!xy3");
+}
+
+#[test]
+fn stack_is_reserved_up_front() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	let stack_bytes = vm.memory_stats().stack_bytes;
+	assert!(stack_bytes > 0);
+
+	let depth = 200;
+	let source = format!("print {}1{};", "1 + (".repeat(depth), ")".repeat(depth));
+	vm.interpret(&source).unwrap();
+	assert_eq!(vm.memory_stats().stack_bytes, stack_bytes);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "201");
+}
+
+#[test]
+fn value_is_copy() {
+	fn assert_copy<T: Copy>() {}
+	assert_copy::<Value>();
+}
+
+#[test]
+fn globals_resolved_to_slots() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	// Slots persist between scripts, redefining a global reuses its slot
+	vm.interpret(r#"var a = "first";"#).unwrap();
+	vm.interpret(r#"var a = "second"; print a;"#).unwrap();
+
+	let result = vm.interpret("print missing;");
+	match result {
+		Err(InterpretError::Runtime {
+			source: RuntimeError::UndefinedVariable(name),
+			..
+		}) => assert_eq!(name, "missing"),
+		result => panic!("{result:?}"),
+	}
+	assert_eq!(vm.memory_stats().globals_count, 1);
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "second");
+}
+
+#[test]
+fn table_dispatch_matches_match_dispatch() {
+	let programs = [
+		"print 1 + 2 * 3 - 4 / 2; print 7 div 2; print -7 % 3; print -7 mod 3; print 2 ** 10;",
+		r#"print "a" + "b"; print 1 < 2; print 1 > 2; print !nil; print nil == false;"#,
+		r#"var a = "global"; { var a = "local"; var b = a ?? 1; print b; } print a;"#,
+		r#"do { print "body"; break; } while (true); while (false) print "never";"#,
+		"var n = 0; { var i = 0; while (i < 3) { n = n + i; i = i + 1; } } print n;",
+		r#"match 2 { 1 => print "one"; 2 => print "two"; _ => print "other"; }"#,
+		r#"try { { var x = 1; throw "boom"; } } catch (e) { print e; } print "after";"#,
+		"print max(3, 1, 2) + abs(-1);",
+		"print 1 + true;",
+		"print undefined;",
+		"-nil;",
+		r#"throw "uncaught";"#,
+		r#"print 1 is Number, "a" is Number;"#,
+		"repeat 3 { print 1; }",
+		"repeat -1 {}",
+	];
+
+	let run = |dispatch, source| {
+		let trace = SharedTrace::default();
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout).with_std_natives();
+		vm.dispatch = dispatch;
+		vm.trace_to(trace.clone());
+		let result = match vm.interpret(source) {
+			Ok(value) => value.to_string(),
+			Err(err) => format!("error: {err}"),
+		};
+		drop(vm);
+		let trace = trace.0.borrow().clone();
+		(String::from_utf8(stdout).unwrap(), result, trace)
+	};
+	for source in programs {
+		assert_eq!(
+			run(Dispatch::Match, source),
+			run(Dispatch::Table, source),
+			"{source}"
+		);
+	}
+}
+
+#[test]
+fn corrupt_bytecode_reports_line() {
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let mut chunk = Chunk::default();
+		chunk.write(OpCode::Nil, 6);
+		chunk.write(OpCode::Pop, 6);
+		chunk.write(OpCode::Pop, 7);
+		chunk.write(OpCode::Return, 7);
+		let mut vm = Vm::new(Vec::new());
+		vm.dispatch = dispatch;
+		assert!(matches!(
+			vm.run(&mut chunk),
+			Err(InterpretError::Runtime {
+				source: RuntimeError::StackUnderflow,
+				line: 7,
+			})
+		));
+
+		// A line table that doesn't cover the code isn't a reason to panic either, not even when
+		// tracing disassembles each instruction
+		let mut chunk = Chunk::default();
+		chunk.write(OpCode::GetLocal, 3);
+		chunk.write(5, 3);
+		chunk.lines.clear();
+		let mut vm = Vm::new(Vec::new());
+		vm.dispatch = dispatch;
+		vm.trace_to(SharedTrace::default());
+		assert!(matches!(
+			vm.run(&mut chunk),
+			Err(InterpretError::Runtime {
+				source: RuntimeError::InvalidBytecode(_),
+				..
+			})
+		));
+
+		// Global slots that the VM's globals don't have
+		for opcode in [OpCode::DefineGlobal, OpCode::GetGlobal, OpCode::SetGlobal] {
+			let mut chunk = Chunk::default();
+			chunk.write(OpCode::Nil, 2);
+			chunk.write(opcode, 3);
+			chunk.write(0, 3);
+			chunk.write(9, 3);
+			chunk.write(OpCode::Return, 3);
+			let mut vm = Vm::new(Vec::new());
+			vm.dispatch = dispatch;
+			let result = vm.run(&mut chunk);
+			assert!(
+				matches!(
+					result,
+					Err(InterpretError::Runtime {
+						source: RuntimeError::InvalidBytecode("global slot out of range"),
+						line: 3,
+					})
+				),
+				"{opcode} {dispatch:?}: {result:?}"
+			);
+		}
+	}
+}
+
+#[test]
+fn constant_out_of_range_is_an_error() {
+	let mut chunk = Chunk::default();
+	chunk.write(OpCode::Constant, 1);
+	chunk.write(3, 1);
+	chunk.write(OpCode::Return, 1);
+	assert!(matches!(
+		chunk.decode_instruction(0),
+		Some(Err(DecodeError::MalformedConstant { offset: 0, idx: 3 }))
+	));
+	assert_eq!(chunk.iter().count(), 1);
+
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let mut vm = Vm::new(Vec::new());
+		vm.dispatch = dispatch;
+		let result = vm.run(&mut chunk);
+		assert!(
+			matches!(
+				result,
+				Err(InterpretError::Decode(DecodeError::MalformedConstant {
+					offset: 0,
+					idx: 3
+				}))
+			),
+			"{dispatch:?}: {result:?}"
+		);
+	}
+}
+
+#[test]
+fn chunk_iter_with_lines() {
+	let mut chunk = Chunk::default();
+	compiler::compile(
+		"print 1;\nprint 2 + 3;",
+		&mut chunk,
+		false,
+		&mut Allocator::default(),
+		&mut Globals::default(),
+	)
+	.unwrap();
+	let lines: Vec<(String, usize, usize)> = chunk
+		.iter()
+		.with_lines()
+		.map(|item| {
+			item.map(|(instruction, offset, line)| (instruction.opcode.to_string(), offset, line))
+		})
+		.collect::<Result<_, _>>()
+		.unwrap();
+	assert_eq!(
+		lines,
+		[
+			("OP_CONSTANT".into(), 0, 1),
+			("OP_PRINT".into(), 2, 1),
+			("OP_CONSTANT".into(), 3, 2),
+			("OP_CONSTANT".into(), 5, 2),
+			("OP_ADD".into(), 7, 2),
+			("OP_PRINT".into(), 8, 2),
+			("OP_RETURN".into(), 9, 2),
+		]
+	);
+}
+
+#[test]
+fn unterminated_string_reports_opening_line() {
+	let mut scanner = Scanner::new("print 1;\nprint \"abc\nxyz\n\n");
+	let err = std::iter::from_fn(|| scanner.scan_token())
+		.find_map(Result::err)
+		.unwrap();
+	assert_eq!(err.to_string(), "[line 2] Unterminated string at '\"abc'");
+}
+
+#[test]
+fn superinstructions() {
+	let mut chunk = Chunk::default();
+	let mut objects = Allocator::default();
+	compiler::compile(
+		"{ var a = 1; var b = 2; print a + b; print a + 3; print 4 + 5; }",
+		&mut chunk,
+		false,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+	chunk.fuse_superinstructions();
+	let disassembly = chunk.disassemble("superinstructions");
+	for opcode in ["OP_LOCAL_LOCAL", "OP_LOCAL_CONSTANT", "OP_CONSTANT_ADD"] {
+		assert!(disassembly.contains(opcode), "{disassembly}");
+	}
+
+	let mut chunk = Chunk::default();
+	compiler::compile(
+		"{ var x = 1; var b = true; print -x; print !b; }",
+		&mut chunk,
+		false,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+	chunk.fuse_superinstructions();
+	let disassembly = chunk.disassemble("superinstructions");
+	for opcode in ["OP_LOCAL_NEGATE", "OP_LOCAL_NOT"] {
+		assert!(disassembly.contains(opcode), "{disassembly}");
+	}
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let mut vm = Vm::new(Vec::new());
+		vm.dispatch = dispatch;
+		vm.superinstructions = true;
+		assert!(matches!(
+			vm.interpret(r#"{ var s = "a"; print -s; }"#),
+			Err(InterpretError::Runtime {
+				source: RuntimeError::InvalidType(_),
+				line: 1,
+			})
+		));
+	}
+
+	let programs = [
+		"{ var a = 1; var b = 2; print a + b; print a + 3; print 4 + 5; }",
+		r#"{ var s = "a"; print s + "b"; print "c" + s; }"#,
+		// `??` jumps straight to the `1` in the middle of the fused `a 1` pair
+		"{ var a = 1; var b = 10; print (b ?? a) + 1; var c = nil; print (c ?? a) + 1; }",
+		"{ var a = true; print a + 1; }",
+		r#"print !1, !"", !nil, -(2 + 1);"#,
+		r#"{ var x = 2; var b = false; var s = ""; var n; print -x, !b, !s, !n, -(-x), !!b; }"#,
+	];
+	let run = |dispatch, superinstructions, source| {
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout);
+		vm.dispatch = dispatch;
+		vm.superinstructions = superinstructions;
+		let result = match vm.interpret(source) {
+			Ok(value) => value.to_string(),
+			Err(err) => format!("error: {err}"),
+		};
+		drop(vm);
+		(String::from_utf8(stdout).unwrap(), result)
+	};
+	for source in programs {
+		let expected = run(Dispatch::Match, false, source);
+		assert_eq!(run(Dispatch::Match, true, source), expected, "{source}");
+		assert_eq!(run(Dispatch::Table, true, source), expected, "{source}");
+	}
+	assert_eq!(
+		run(Dispatch::Match, true, programs[2]).0,
+		"112",
+		"{}",
+		programs[2]
+	);
+}
+
+#[test]
+fn increment_local_superinstruction() {
+	let disassemble = |source| {
+		let mut chunk = Chunk::default();
+		compiler::compile(
+			source,
+			&mut chunk,
+			false,
+			&mut Allocator::default(),
+			&mut Globals::default(),
+		)
+		.unwrap();
+		chunk.fuse_superinstructions();
+		chunk.disassemble("increment local")
+	};
+	let disassembly = disassemble("{ var i = 0; while (i < 3) i = i + 1; }");
+	assert!(disassembly.contains("OP_INCREMENT_LOCAL"), "{disassembly}");
+	// Reading one local and writing another isn't an increment
+	let disassembly = disassemble("{ var a = 1; var b = 2; a = b + 1; }");
+	assert!(!disassembly.contains("OP_INCREMENT_LOCAL"), "{disassembly}");
+
+	let programs = [
+		"{ var i = 0; while (i < 1000) i = i + 1; print i; }",
+		"{ var x = 0.5; x = x + 0.25; x = x + -1; print x; print x = x + 2; }",
+		r#"{ var s = "a"; s = s + "b"; print s = s + "c"; }"#,
+		"{ var a = true; a = a + 1; }",
+		"{ var a = 1; var b = 2; a = b + 1; b = b + 1; print a, b; }",
+		// `??` jumps to the `1` in the middle of the fused `b = b + 1`, adding it to `a` instead
+		"{ var a = 10; var b = 1; b = (a ?? b) + 1; print b; a = nil; b = (a ?? b) + 1; print b; }",
+	];
+	let run = |dispatch, superinstructions, source| {
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout);
+		vm.dispatch = dispatch;
+		vm.superinstructions = superinstructions;
+		let result = match vm.interpret(source) {
+			Ok(value) => value.to_string(),
+			Err(err) => format!("error: {err}"),
+		};
+		drop(vm);
+		(String::from_utf8(stdout).unwrap(), result)
+	};
+	for source in programs {
+		let expected = run(Dispatch::Match, false, source);
+		assert_eq!(run(Dispatch::Match, true, source), expected, "{source}");
+		assert_eq!(run(Dispatch::Table, true, source), expected, "{source}");
+	}
+	assert_eq!(run(Dispatch::Table, true, programs[0]).0, "1000");
+	assert_eq!(run(Dispatch::Table, true, programs[5]).0, "1112");
+}
+
+#[test]
+fn non_finite_numbers() {
+	assert_eq!(run_and_capture_stdout("print 1 / 0;"), "inf");
+	assert_eq!(run_and_capture_stdout("print -1 / 0;"), "-inf");
+	assert_eq!(run_and_capture_stdout("print 0 / 0;"), "nan");
+	assert_eq!(run_and_capture_stdout("print -(0 / 0);"), "nan");
+	// NaN isn't equal to anything, itself included
+	assert_eq!(
+		run_and_capture_stdout("{ var n = 0 / 0; print n == n; }"),
+		"false"
+	);
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_prelude();
+	vm.interpret("print INFINITY; print -INFINITY; print NAN;")
+		.unwrap();
+	assert_eq!(String::from_utf8(stdout).unwrap(), "inf-infnan");
+}
+
+#[test]
+fn number_formatting() {
+	let cases = [
+		("14", "14"),
+		("3.2", "3.2"),
+		("18.8125", "18.8125"),
+		("100000000000000000000", "1e+20"),
+		("123456", "123456"),
+		("1234567", "1.23457e+06"),
+		("999999.5", "1e+06"),
+		("0.0001", "0.0001"),
+		("0.00001234", "1.234e-05"),
+		("-2.5", "-2.5"),
+		("1 / 3", "0.333333"),
+		("-0", "-0"),
+	];
+	for (expression, expected) in cases {
+		assert_eq!(
+			run_and_capture_stdout(&format!("print {expression};")),
+			expected,
+			"{expression}"
+		);
+	}
+}
+
+#[test]
+fn assignment() {
+	assert_eq!(
+		run_and_capture_stdout(
+			"var a = 1; var b; a = b = a + 1; print a; print b; { var c = 1; c = c + a; print c; }"
+		),
+		"223"
+	);
+	// Counting loops work now that locals can change
+	assert_eq!(
+		run_and_capture_stdout("{ var i = 0; while (i < 3) { print i; i = i + 1; } }"),
+		"012"
+	);
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret("var a = 1; var b = 2; a + b = 3;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::InvalidAssignmentTarget,
+			..
+		})
+	));
+	assert!(matches!(
+		vm.interpret("undefined = 1;"),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::UndefinedVariable(_),
+			..
+		})
+	));
+}
+
+#[test]
+fn local_slots_in_disassembly() {
+	let mut chunk = Chunk::default();
+	let mut objects = Allocator::default();
+	compiler::compile(
+		"{ var a = 1; var b = 2; b = a; }",
+		&mut chunk,
+		false,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+	let disassembly = chunk.disassemble("locals");
+	assert!(
+		disassembly
+			.contains("0004    | OP_GET_LOCAL          0\n0006    | OP_SET_LOCAL          1"),
+		"{disassembly}"
+	);
+}
+
+static SIDE_EFFECTS: AtomicUsize = AtomicUsize::new(0);
+
+fn side_effect(_: &mut native::Context, _: &[Value]) -> Result<Value, RuntimeError> {
+	SIDE_EFFECTS.fetch_add(1, Ordering::SeqCst);
+	Ok(Value::Bool(true))
+}
+
+#[test]
+fn conditional_expression() {
+	assert_eq!(
+		run_and_capture_stdout(r#"print true ? "yes" : "no"; print nil ? "yes" : "no";"#),
+		"yesno"
+	);
+	// Right associative and lower precedence than `??`
+	assert_eq!(
+		run_and_capture_stdout("print false ? 1 : nil ?? false ? 2 : 3;"),
+		"3"
+	);
+	assert_eq!(
+		run_and_capture_stdout("var a = 1 < 2 ? 10 : 20; print a;"),
+		"10"
+	);
+	// Only the branch that's taken runs
+	assert_eq!(
+		run_and_capture_stdout("var a = 0; var b = 0; true ? (a = 1) : (b = 1); print a; print b;"),
+		"10"
+	);
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.define_native("sideEffect", Some(0), side_effect)
+		.unwrap();
+	vm.interpret(r#"print sideEffect() ? "a" : "b";"#).unwrap();
+	assert_eq!(SIDE_EFFECTS.load(Ordering::SeqCst), 1);
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "a");
+}
+
+#[test]
+fn print_multiple_values() {
+	assert_eq!(run_and_capture_stdout("print 1, 2, 3;"), "1 2 3");
+	assert_eq!(
+		run_and_capture_stdout(r#"var a = "a"; print a, nil, 1 + 1;"#),
+		"a nil 2"
+	);
+}
+
+/// Runs the REPL binary with `input` piped to its stdin and returns what it printed
+fn run_repl(input: &str) -> String {
+	String::from_utf8(repl_output(input).stdout).unwrap()
+}
+
+fn repl_output(input: &str) -> std::process::Output {
+	lox_output(&[], input)
+}
+
+/// Runs the binary with `args`, feeding it `input` on stdin
+fn lox_output(args: &[&str], input: &str) -> std::process::Output {
+	use std::io::Write;
+	use std::process::{Command, Stdio};
+
+	let mut child = Command::new(env!("CARGO_BIN_EXE_lox-v2"))
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.unwrap();
+	child
+		.stdin
+		.take()
+		.unwrap()
+		.write_all(input.as_bytes())
+		.unwrap();
+	child.wait_with_output().unwrap()
+}
+
+#[test]
+fn repl_meta_commands() {
+	let output = run_repl("var x = 1;\n:globals\n:dump\nprint x;\n");
+	assert!(output.contains("\nx = 1\n"), "{output}");
+	assert!(output.contains("OP_DEFINE_GLOBAL"), "{output}");
+	assert!(output.ends_with("1"), "{output}");
+}
+
+#[test]
+fn reset_all() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.interpret(r#"var a = "a"; var b = a + "b";"#).unwrap();
+	vm.reset_all();
+	assert_eq!(vm.memory_stats().object_count, 0);
+	assert_eq!(vm.globals().count(), 0);
+	assert!(matches!(
+		vm.interpret("print a;"),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::UndefinedVariable(_),
+			..
+		})
+	));
+	vm.interpret(r#"var a = "a"; print a + "b";"#).unwrap();
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "ab");
+
+	let output = run_repl("var x = 1;\n:reset\n:globals\nprint PI;\n");
+	assert!(!output.contains("x = 1"), "{output}");
+	assert!(output.ends_with("3.14159"), "{output}");
+}
+
+#[test]
+fn repl_skips_blank_lines() {
+	let output = repl_output("\n   \n// just a comment\n\t// indented comment\n");
+	assert!(output.status.success());
+	assert!(output.stdout.is_empty());
+	assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn interpret_returns_final_expression() {
+	let mut vm = Vm::new(Vec::new());
+	assert_eq!(vm.interpret("1 + 2").unwrap(), Value::Number(3.0));
+	assert_eq!(
+		vm.interpret("var a = 4; a * 2;").unwrap(),
+		Value::Number(8.0)
+	);
+	assert_eq!(vm.interpret("1; print 1;").unwrap(), Value::Nil);
+	assert_eq!(vm.interpret("print 1;").unwrap(), Value::Nil);
+	assert_eq!(vm.interpret("{ 1; }").unwrap(), Value::Nil);
+	assert_eq!(
+		vm.interpret("var i = 0; while (i < 3) i = i + 1;").unwrap(),
+		Value::Nil
+	);
+	assert!(vm.interpret("1 2").is_err());
+}
+
+#[test]
+fn equality_across_types() {
+	assert_eq!(
+		run_and_capture_stdout(
+			r#"print 1 == 1.0; print nil == false; print "1" == 1; print nil == nil; print "a" == "a";"#
+		),
+		"truefalsefalsetruetrue"
+	);
+}
+
+#[test]
+fn assignment_in_condition_warns() {
+	let warnings = SharedTrace::default();
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.warnings_to(warnings.clone());
+	vm.interpret(
+		"var x = 0;\nwhile (x = nil) print x;\ndo print 1; while (x = false);\n\
+		 while (x = (1)) break;\nwhile (x = abs(1)) break;",
+	)
+	.unwrap();
+	assert_eq!(
+		*warnings.0.borrow(),
+		"[line 2] Warning: Assignment used as a condition, did you mean '=='? Wrap it in parentheses if not\n\
+		 [line 3] Warning: Assignment used as a condition, did you mean '=='? Wrap it in parentheses if not\n\
+		 [line 4] Warning: Assignment used as a condition, did you mean '=='? Wrap it in parentheses if not\n\
+		 [line 5] Warning: Assignment used as a condition, did you mean '=='? Wrap it in parentheses if not\n"
+	);
+
+	warnings.0.borrow_mut().clear();
+	vm.interpret(
+		"while ((x = nil)) {} while (x == 1) {} while ((x = 1) == 2) {} while (x) x = nil;",
+	)
+	.unwrap();
+	assert_eq!(*warnings.0.borrow(), "");
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "1");
+}
+
+#[test]
+fn opcode_bytes_round_trip() {
+	for (byte, opcode) in OpCode::ALL.iter().enumerate() {
+		assert_eq!(*opcode as usize, byte);
+		assert_eq!(OpCode::try_from(byte as u8).unwrap() as u8, byte as u8);
+	}
+	assert!(OpCode::try_from(OpCode::ALL.len() as u8).is_err());
+	assert!(OpCode::try_from(u8::MAX).is_err());
+}
+
+#[test]
+fn trailing_comma_in_arguments() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret("print max(1, 3,); print abs(\n\t-2,\n);")
+		.unwrap();
+	assert!(vm.interpret("max(,);").is_err());
+	assert!(vm.interpret("max(1,,);").is_err());
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "32");
+}
+
+#[test]
+fn keywords() {
+	fn scan(word: &str) -> TokenKind<'_> {
+		Scanner::new(word).scan_token().unwrap().unwrap().kind
+	}
+
+	let keywords = [
+		("and", TokenKind::And),
+		("break", TokenKind::Break),
+		("catch", TokenKind::Catch),
+		("class", TokenKind::Class),
+		("continue", TokenKind::Continue),
+		("div", TokenKind::Div),
+		("do", TokenKind::Do),
+		("else", TokenKind::Else),
+		("false", TokenKind::False),
+		("for", TokenKind::For),
+		("fun", TokenKind::Fun),
+		("if", TokenKind::If),
+		("import", TokenKind::Import),
+		("match", TokenKind::Match),
+		("mod", TokenKind::Mod),
+		("nil", TokenKind::Nil),
+		("or", TokenKind::Or),
+		("print", TokenKind::Print),
+		("return", TokenKind::Return),
+		("super", TokenKind::Super),
+		("this", TokenKind::This),
+		("throw", TokenKind::Throw),
+		("true", TokenKind::True),
+		("try", TokenKind::Try),
+		("var", TokenKind::Var),
+		("while", TokenKind::While),
+	];
+	for (word, kind) in keywords {
+		assert_eq!(scan(word), kind, "{word}");
+	}
+	for word in [
+		"fo", "truex", "thisis", "t", "f", "an", "dov", "Print", "_if", "whiles",
+	] {
+		assert_eq!(scan(word), TokenKind::Identifier(word), "{word}");
+	}
+}
+
+#[test]
+fn true_is_a_literal() {
+	let mut chunk = Chunk::default();
+	compiler::compile(
+		"print true; print false;",
+		&mut chunk,
+		false,
+		&mut Allocator::default(),
+		&mut Globals::default(),
+	)
+	.unwrap();
+	let disassembly = chunk.disassemble("literals");
+	assert!(disassembly.contains("OP_TRUE"), "{disassembly}");
+	assert!(disassembly.contains("OP_FALSE"), "{disassembly}");
+	assert!(!disassembly.contains("OP_GET_GLOBAL"), "{disassembly}");
+	assert_eq!(run_and_capture_stdout("print true;"), "true");
+}
+
+#[test]
+fn globals_capacity() {
+	let count = 100;
+	let mut vm = Vm::new(Vec::new()).with_globals_capacity(count);
+	let globals_bytes = vm.memory_stats().globals_bytes;
+	assert!(globals_bytes > 0);
+
+	let source: String = (0..count).map(|i| format!("var g{i} = {i};")).collect();
+	vm.interpret(&source).unwrap();
+	assert_eq!(vm.memory_stats().globals_count, count);
+	assert_eq!(vm.memory_stats().globals_bytes, globals_bytes);
+}
+
+#[test]
+fn parse_tree() {
+	assert_eq!(compiler::parse_tree("1 + 2 * 3").unwrap(), "(+ 1 (* 2 3))");
+	assert_eq!(
+		compiler::parse_tree("(1 + 2) * -3 ** 2;").unwrap(),
+		"(* (group (+ 1 2)) (- (** 3 2)))"
+	);
+	assert_eq!(
+		compiler::parse_tree(
+			r#"var a = nil; a = b ?? c ? max(1, "x") : !d;
+			while (a < 3) { print a, a >= 1; break; }"#
+		)
+		.unwrap(),
+		"(var a nil)\n\
+		 (= a (?: (?? b c) (call max 1 \"x\") (! d)))\n\
+		 (while (< a 3) (block (print a (>= a 1)) (break)))"
+	);
+	assert!(compiler::parse_tree("1 +").is_err());
+}
+
+#[test]
+fn chunk_accessors() {
+	let mut chunk = Chunk::default();
+	assert_eq!(chunk.code_len(), 0);
+	let idx = chunk.write_constant(Value::Number(1.5));
+	chunk.write(OpCode::Constant, 4);
+	chunk.write(idx as u8, 4);
+	chunk.write(OpCode::Return, 5);
+
+	assert_eq!(
+		chunk.code(),
+		[OpCode::Constant as u8, idx as u8, OpCode::Return as u8]
+	);
+	assert_eq!(chunk.code_len(), 3);
+	assert_eq!(chunk.constants(), [Value::Number(1.5)]);
+	assert_eq!(chunk.line_at(0), Some(4));
+	assert_eq!(chunk.line_at(2), Some(5));
+	assert_eq!(chunk.line_at(3), None);
+}
+
+#[test]
+fn script_from_stdin() {
+	let output = lox_output(&["-"], "var a = 1;\nprint a + 1;\n");
+	assert!(output.status.success());
+	assert_eq!(String::from_utf8(output.stdout).unwrap(), "2");
+}
+
+#[test]
+fn print_each_object_kind() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret(r#"print "abc", abs;"#).unwrap();
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "abc <native fn abs>");
+}
+
+#[test]
+fn downcast_checks_the_kind() {
+	let mut objects = Allocator::default();
+	let string = objects.copy_string("abc").unwrap();
+	let native = objects.new_native("abs", Some(1), native::abs).unwrap();
+	unsafe {
+		assert_eq!((*string).downcast::<ObjString>().unwrap().as_str(), "abc");
+		assert!((*string).downcast::<ObjNative>().is_none());
+		assert_eq!((*native).downcast::<ObjNative>().unwrap().name, "abs");
+		assert!((*native).downcast_mut::<ObjString>().is_none());
+	}
+}
+
+#[test]
+fn intern_stats() {
+	let mut objects = Allocator::default();
+	let a = objects.copy_string("a").unwrap();
+	assert_eq!(objects.copy_string("a").unwrap(), a);
+	assert_eq!(objects.take_string("a".to_string()).unwrap(), a);
+	objects.copy_string("b").unwrap();
+	let stats = objects.intern_stats();
+	assert_eq!((stats.hits, stats.misses), (2, 2));
+
+	objects.set_interning(false);
+	assert_ne!(objects.copy_string("a").unwrap(), a);
+	assert_eq!(objects.intern_stats(), stats);
+	// Names are interned regardless
+	assert_eq!(objects.intern("a").unwrap(), a);
+	assert_eq!(objects.intern_stats().hits, 3);
+}
+
+#[test]
+fn strings_equal_by_contents_without_interning() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.set_interning(false);
+	vm.interpret(r#"var a = "ab"; var b = "a" + "b"; print a == b, a == "ba";"#)
+		.unwrap();
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "true false");
+}
+
+#[test]
+fn string_lengths() {
+	let mut objects = Allocator::default();
+	let str = objects.copy_string("zażółć").unwrap();
+	let str = unsafe { (*str).downcast::<ObjString>().unwrap() };
+	assert_eq!(str.byte_len(), 10);
+	assert_eq!(str.char_len(), 6);
+}
+
+#[test]
+fn nesting_too_deep() {
+	let parens = |depth: usize| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+	assert_eq!(run_and_capture_stdout(&parens(200)), "1");
+
+	for source in [
+		parens(10_000),
+		format!("print {}1;", "-".repeat(10_000)),
+		format!("var a = 1; print a{};", " ** a".repeat(10_000)),
+		format!("{}{}", "{".repeat(10_000), "}".repeat(10_000)),
+		format!("{}1;", "while (false) ".repeat(10_000)),
+	] {
+		let mut vm = Vm::new(Vec::new());
+		assert!(matches!(
+			vm.interpret(&source),
+			Err(InterpretError::Compile {
+				source: compiler::Error::NestingTooDeep {
+					limit: compiler::DEFAULT_MAX_NESTING
+				},
+				..
+			})
+		));
+	}
+
+	let mut vm = Vm::new(Vec::new());
+	vm.max_nesting = 10;
+	assert!(vm.interpret(&parens(20)).is_err());
+	assert!(matches!(
+		compiler::compile_with_max_nesting(
+			&parens(20),
+			&mut Chunk::default(),
+			false,
+			&mut Allocator::default(),
+			&mut Globals::default(),
+			10,
+		),
+		Err(compiler::Error::NestingTooDeep { limit: 10 })
+	));
+}
+
+#[test]
+fn negative_literals_are_folded() {
+	let opcodes = |source: &str| {
+		let mut chunk = Chunk::default();
+		compiler::compile(
+			source,
+			&mut chunk,
+			false,
+			&mut Allocator::default(),
+			&mut Globals::default(),
+		)
+		.unwrap();
+		chunk
+			.iter()
+			.map(|instruction| instruction.unwrap().opcode.to_string())
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(
+		opcodes("print -5;"),
+		["OP_CONSTANT", "OP_PRINT", "OP_RETURN"]
+	);
+	assert_eq!(
+		opcodes("var x = 5; print -x;"),
+		[
+			"OP_CONSTANT",
+			"OP_DEFINE_GLOBAL",
+			"OP_GET_GLOBAL",
+			"OP_NEGATE",
+			"OP_PRINT",
+			"OP_RETURN"
+		]
+	);
+
+	assert_eq!(
+		run_and_capture_stdout("print -5, - -5, -(2), -2 ** 2, -0;"),
+		"-5 5 -2 -4 -0"
+	);
+	// Operands that only end with a literal are negated at runtime
+	assert_eq!(
+		run_and_capture_stdout(
+			"var c = true; var a = 5; var n; var y = 3;\n\
+			 print -(c ? 1 : 2), -(true ? 1 : 2), -(a ?? 3), -(n ?? 3), -(5 ?? 3), -(y > 1 ? 2 : 9);"
+		),
+		"-1 -1 -5 -3 -5 -2"
+	);
+}
+
+#[test]
+fn safe_value_accessors() {
+	let mut vm = Vm::new(Vec::new());
+	let value = vm
+		.interpret(r#"var greeting = "hello" + " world"; greeting"#)
+		.unwrap();
+	assert_eq!(vm.string(value), Some("hello world"));
+	assert_eq!(value.as_f64(), None);
+
+	let (_, greeting) = vm.globals().find(|(name, _)| *name == "greeting").unwrap();
+	assert_eq!(vm.string(greeting), Some("hello world"));
+
+	assert_eq!(vm.interpret("1 + 2").unwrap().as_f64(), Some(3.0));
+	assert_eq!(vm.interpret("1 < 2").unwrap().as_bool(), Some(true));
+	assert_eq!(vm.interpret("nil").unwrap().as_bool(), None);
+	let three = vm.interpret("3").unwrap();
+	assert_eq!(vm.string(three), None);
+
+	// The string is freed along with everything else, the value still points at it
+	vm.reset_all();
+	assert_eq!(vm.string(value), None);
+	assert_eq!(Vm::new(Vec::new()).string(value), None);
+}
+
+#[test]
+fn comparisons_mixing_integral_and_fractional_numbers() {
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout);
+		vm.dispatch = dispatch;
+		vm.interpret("print 1 < 2.5, 2.5 > 1, 3 <= 3, 3.0 >= 3, 2.5 <= 2;")
+			.unwrap();
+		assert!(matches!(
+			vm.interpret(r#"print 1 < "2";"#),
+			Err(InterpretError::Runtime {
+				source: RuntimeError::InvalidType(_),
+				..
+			})
+		));
+		drop(vm);
+		assert_eq!(
+			String::from_utf8(stdout).unwrap(),
+			"true true true true false"
+		);
+	}
+}
+
+#[test]
+fn assert_natives() {
+	let mut vm = Vm::new(Vec::new()).with_std_natives();
+	vm.interpret(r#"assertEq(1 + 2, 3); assertEq("a" + "b", "ab"); assertNeq(1, "1");"#)
+		.unwrap();
+
+	let err = vm.interpret(r#"assertEq(1, "1");"#).unwrap_err();
+	assert!(matches!(
+		err,
+		InterpretError::Runtime {
+			source: RuntimeError::AssertionFailed { .. },
+			line: 1,
+		}
+	));
+	assert!(
+		err.to_string().contains(r#"Assertion failed: 1 == "1""#),
+		"{err}"
+	);
+	assert!(vm.interpret("assertNeq(nil, nil);").is_err());
+}
+
+#[test]
+fn semicolon_optional_at_end_of_input() {
+	assert_eq!(run_and_capture_stdout("print 1"), "1");
+	assert_eq!(run_and_capture_stdout("var a = 2;\nprint a"), "2");
+	assert_eq!(run_and_capture_stdout("var a = 3"), "");
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret("print 1 print 2"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedToken { token: ";", .. },
+			..
+		})
+	));
+}
+
+#[test]
+fn compile_to_chunk() {
+	let mut objects = Allocator::default();
+	let mut chunk = compiler::compile_to_chunk(
+		r#"{ var a = 1; var b = "x"; print a + 2, b; }"#,
+		&mut objects,
+		&mut Globals::default(),
+	)
+	.unwrap();
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.run(&mut chunk).unwrap();
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "3 x");
+
+	// A chunk using globals has to be compiled by the VM that runs it
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	let mut chunk = vm
+		.compile(r#"var n = 1; var s = "n"; n = n * 2; print s, n;"#)
+		.unwrap();
+	assert!(vm.globals().next().is_none());
+	vm.run(&mut chunk).unwrap();
+	vm.run(&mut chunk).unwrap();
+	assert!(matches!(
+		vm.compile("print 1 +;"),
+		Err(InterpretError::Compile { .. })
+	));
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "n 2n 2");
+}
+
+#[test]
+fn duplicate_local_declaration() {
+	let mut vm = Vm::new(Vec::new());
+	let err = vm.interpret("{ var a = 1; var a = 2; }").unwrap_err();
+	assert!(
+		matches!(
+			&err,
+			InterpretError::Compile { source: compiler::Error::DuplicateDeclaration { name }, .. } if name == "a"
+		),
+		"{err}"
+	);
+
+	assert_eq!(
+		run_and_capture_stdout(
+			"var a = 0; var a = 1; { var a = 2; { var a = 3; print a; } print a; } print a;"
+		),
+		"321"
+	);
+}
+
+#[test]
+fn this_outside_class() {
+	let mut vm = Vm::new(Vec::new());
+	let err = vm.interpret("print this;").unwrap_err();
+	assert!(matches!(
+		err,
+		InterpretError::Compile {
+			source: compiler::Error::ThisOutsideClass,
+			..
+		}
+	));
+	assert_eq!(
+		err.to_string(),
+		"[line 1] Compile: Can't use 'this' outside of a class"
+	);
+}
+
+#[test]
+fn newline_terminators() {
+	let source =
+		"var a = 1\nvar b = a +\n  2\nprint a, b\n{ var c = b * 2\n print c }\nprint 4; print 5\n";
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret(source),
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedToken { token: ";", .. },
+			..
+		})
+	));
+
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.newline_terminators = true;
+	vm.interpret(source).unwrap();
+	// Statements on the same line still need a `;` between them
+	assert!(vm.interpret("print 1 print 2").is_err());
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "1 3645");
+}
+
+#[test]
+fn disassembly_columns_line_up() {
+	assert_eq!(OpCode::NAME_WIDTH, "OP_JUMP_IF_NOT_NIL".len());
+
+	let mut chunk = Chunk::default();
+	compiler::compile(
+		"var a; { var b = 2; print a ?? b; }",
+		&mut chunk,
+		false,
+		&mut Allocator::default(),
+		&mut Globals::default(),
+	)
+	.unwrap();
+	let disassembly = chunk.disassemble("columns");
+	assert!(disassembly.contains("OP_JUMP_IF_NOT_NIL"), "{disassembly}");
+
+	// Offset and line take 10 characters, operands start after the name column and a space
+	for line in disassembly.lines().skip(1) {
+		let (name, rest) = line[10..].split_at(OpCode::NAME_WIDTH);
+		assert!(name.starts_with("OP_"), "{line}");
+		assert!(rest.starts_with(' '), "{line}");
+	}
+}
+
+#[test]
+fn print_to_broken_writer() {
+	struct Broken;
+
+	impl std::io::Write for Broken {
+		fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+			Err(std::io::ErrorKind::BrokenPipe.into())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		let mut vm = Vm::new(Broken);
+		vm.dispatch = dispatch;
+		assert!(matches!(
+			vm.interpret("print 1, 2;"),
+			Err(InterpretError::Io(err)) if err.kind() == std::io::ErrorKind::BrokenPipe
+		));
+	}
+}
+
+#[test]
+fn number_formatting_natives() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret(
+		"print hex(255), oct(8), bin(5), hex(0), toFixed(3.14159, 2), toFixed(2.5, 0), toFixed(1, 3);",
+	)
+	.unwrap();
+	for source in [
+		"hex(-1);",
+		"bin(1.5);",
+		"toFixed(1, -1);",
+		"toFixed(\"1\", 2);",
+	] {
+		assert!(
+			matches!(vm.interpret(source), Err(InterpretError::Runtime { .. })),
+			"{source}"
+		);
+	}
+	drop(vm);
+	assert_eq!(
+		String::from_utf8(stdout).unwrap(),
+		"ff 10 101 0 3.14 2 1.000"
+	);
+}
+
+#[test]
+fn is_checks_builtin_types() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret(
+		"var s = \"a\"; \
+		print 5 is Number, s is Number, s is String, nil is Nil, false is Bool, abs is Function, \
+		1 + 2 is Number == true;",
+	)
+	.unwrap();
+	assert!(matches!(
+		vm.interpret("print 1 is Foo;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::UnknownType { .. },
+			..
+		})
+	));
+	assert!(matches!(
+		vm.interpret("print 1 is 2;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedTypeName,
+			..
+		})
+	));
+	drop(vm);
+	assert_eq!(
+		String::from_utf8(stdout).unwrap(),
+		"true false true true true true true"
+	);
+}
+
+#[test]
+fn snapshot_restores_globals() {
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout).with_std_natives();
+	vm.interpret("var n = 1.5; var s = \"hi\"; var b = true; var z = nil; var f = abs;")
+		.unwrap();
+	let snapshot = vm.snapshot();
+	assert_eq!(
+		snapshot.globals,
+		[
+			("n".to_string(), SnapshotValue::Number(1.5)),
+			("s".to_string(), SnapshotValue::String("hi".to_string())),
+			("b".to_string(), SnapshotValue::Bool(true)),
+			("z".to_string(), SnapshotValue::Nil),
+		]
+	);
+
+	vm.reset_all();
+	assert!(vm.interpret("print n;").is_err());
+	vm.restore(&snapshot).unwrap();
+	vm.interpret("print n, s + \"!\", b, z, s == \"hi\";")
+		.unwrap();
+	assert!(vm.interpret("f;").is_err());
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "1.5 hi! true nil true");
+}
+
+#[test]
+fn unreachable_code_warns() {
+	let warnings = SharedTrace::default();
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	vm.warnings_to(warnings.clone());
+	vm.interpret(
+		"var i = 0;\nwhile (i < 2) {\n  i = i + 1;\n  print i;\n  continue;\n  print \"dead\";\n  print \"dead\";\n}\n\
+		 do { break; print \"dead\"; } while (false);",
+	)
+	.unwrap();
+	assert_eq!(
+		*warnings.0.borrow(),
+		"[line 6] Warning: Unreachable code after 'continue'\n\
+		 [line 9] Warning: Unreachable code after 'break'\n"
+	);
+
+	warnings.0.borrow_mut().clear();
+	vm.interpret(
+		"while (true) { while (true) break; print i; break; } try { throw 1; } catch (e) {}",
+	)
+	.unwrap();
+	assert_eq!(*warnings.0.borrow(), "");
+	drop(vm);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "122");
+}
+
+#[test]
+fn repeat_loop() {
+	assert_eq!(
+		run_and_capture_stdout("repeat 3 { print \"hi\"; }"),
+		"hihihi"
+	);
+	assert_eq!(
+		run_and_capture_stdout(
+			"var n = 0; repeat 2 + 3 { n = n + 1; var n = 10; print n; } print n; repeat 0 { print 1; }"
+		),
+		"10101010105"
+	);
+	assert_eq!(
+		run_and_capture_stdout(
+			"var i = 0; repeat 10 { i = i + 1; match i { 2 => continue; 5 => break; _ => print i; } }"
+		),
+		"134"
+	);
+
+	let mut vm = Vm::new(Vec::new());
+	for source in ["repeat -1 {}", "repeat 1.5 {}", "repeat \"3\" {}"] {
+		assert!(
+			matches!(
+				vm.interpret(source),
+				Err(InterpretError::Runtime {
+					source: RuntimeError::InvalidType(_),
+					..
+				})
+			),
+			"{source}"
+		);
+	}
+	assert!(matches!(
+		vm.interpret("repeat 3 print 1;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedToken { .. },
+			..
+		})
+	));
+}
+
+#[test]
+fn foreign_values_round_trip() {
+	struct Widget {
+		id: u32,
+		dropped: Rc<Cell<bool>>,
+	}
+	impl Drop for Widget {
+		fn drop(&mut self) {
+			self.dropped.set(true);
+		}
+	}
+
+	let dropped = Rc::new(Cell::new(false));
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	let widget = vm
+		.make_foreign(
+			"Widget",
+			Widget {
+				id: 7,
+				dropped: dropped.clone(),
+			},
+		)
+		.unwrap();
+	vm.define_global("widget", widget).unwrap();
+	vm.interpret("var copy = widget; print copy, copy == widget, copy is Foreign;")
+		.unwrap();
+
+	let (_, copy) = vm.globals().find(|(name, _)| *name == "copy").unwrap();
+	assert_eq!(vm.foreign::<Widget>(copy).map(|w| w.id), Some(7));
+	assert!(vm.foreign::<u32>(copy).is_none());
+	assert!(vm.foreign::<Widget>(Value::Number(7.0)).is_none());
+	assert_eq!(vm.type_of(copy), Some(Type::Foreign));
+
+	assert!(!dropped.get());
+	vm.reset_all();
+	assert!(dropped.get());
+	// The global kept a copy of the pointer to the freed object
+	assert!(vm.foreign::<Widget>(copy).is_none());
+	assert_eq!(vm.type_of(copy), None);
+	drop(vm);
+	assert_eq!(
+		String::from_utf8(stdout).unwrap(),
+		"<foreign Widget> true true"
+	);
+}
+
+#[test]
+fn print_hook_sees_every_value() {
+	let printed = Rc::new(RefCell::new(Vec::new()));
+	for dispatch in [Dispatch::Match, Dispatch::Table] {
+		printed.borrow_mut().clear();
+		let mut stdout = Vec::new();
+		let mut vm = Vm::new(&mut stdout);
+		vm.dispatch = dispatch;
+		let sink = printed.clone();
+		vm.on_print(move |value: &Value| sink.borrow_mut().push(value.to_string()));
+		vm.interpret("print 1, \"two\"; print nil; var x = true; print x;")
+			.unwrap();
+		drop(vm);
+		assert_eq!(*printed.borrow(), ["1", "two", "nil", "true"]);
+		assert_eq!(String::from_utf8(stdout).unwrap(), "1 twoniltrue");
+	}
+}
+
+#[test]
+fn missing_global_hook_supplies_values() {
+	let asked = Rc::new(RefCell::new(Vec::new()));
+	let mut stdout = Vec::new();
+	let mut vm = Vm::new(&mut stdout);
+	let log = asked.clone();
+	vm.on_missing_global(move |name| {
+		log.borrow_mut().push(name.to_string());
+		(name == "answer").then_some(Value::Number(42.0))
+	});
+	vm.interpret("print answer; print answer + 1;").unwrap();
+	assert!(matches!(
+		vm.interpret("print question;"),
+		Err(InterpretError::Runtime {
+			source: RuntimeError::UndefinedVariable(_),
+			..
+		})
+	));
+	drop(vm);
+	assert_eq!(*asked.borrow(), ["answer", "question"]);
+	assert_eq!(String::from_utf8(stdout).unwrap(), "4243");
+}
+
+#[test]
+fn compile_errors_have_lines() {
+	let mut vm = Vm::new(Vec::new());
+	let err = vm
+		.interpret("var a = 1;\nvar b = 2;\nprint a +;\nprint b;")
+		.unwrap_err();
+	assert_eq!(err.to_string(), "[line 3] Compile: Expected expression");
+
+	let err = vm.interpret("print 1;\n\n\"unterminated\n\n").unwrap_err();
+	assert!(
+		matches!(err, InterpretError::Compile { line: 3, .. }),
+		"{err:?}"
+	);
+
+	let output = lox_output(&["-"], "print 1;\nprint ;");
+	assert!(String::from_utf8(output.stderr)
+		.unwrap()
+		.contains("[line 2] Compile: Expected expression"),);
+}
+
+#[test]
+fn labeled_break_and_continue() {
+	assert_eq!(
+		run_and_capture_stdout(
+			"var i = 0;
+			outer: while (true) {
+				i = i + 1;
+				var j = 0;
+				while (true) {
+					j = j + 1;
+					inner: repeat 1 { match j { 2 => continue outer; _ => match i { 3 => break outer; _ => print j; } } }
+				}
+			}
+			print i;"
+		),
+		"113"
+	);
+	assert_eq!(
+		run_and_capture_stdout(
+			"var n = 0; rows: repeat 3 { { var a = 1; cols: repeat 3 { n = n + a; continue rows; } } } print n;"
+		),
+		"3"
+	);
+
+	let mut vm = Vm::new(Vec::new());
+	assert!(matches!(
+		vm.interpret("outer: while (true) { break inner; }"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::UnknownLabel { .. },
+			..
+		})
+	));
+	assert!(matches!(
+		vm.interpret("outer: print 1;"),
+		Err(InterpretError::Compile {
+			source: compiler::Error::ExpectedLoopAfterLabel,
+			..
+		})
+	));
+}